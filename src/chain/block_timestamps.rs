@@ -0,0 +1,57 @@
+use mongodb::bson::DateTime;
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use web3::types::{Block, H256};
+use crate::error::{Error, Result};
+
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// Bounded cache of block timestamps keyed by block hash, so that the many
+/// logs of a single block only cost one `eth_getBlockByHash` call.
+pub struct BlockTimestamps {
+    capacity: usize,
+    entries: Mutex<(HashMap<H256, DateTime>, VecDeque<H256>)>,
+}
+
+impl Default for BlockTimestamps {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl BlockTimestamps {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new((HashMap::new(), VecDeque::new())),
+        }
+    }
+
+    /// Returns the timestamp of block `hash`, calling `fetch` on a cache miss.
+    pub async fn get_or_fetch<F, Fut>(&self, hash: H256, fetch: F) -> Result<DateTime>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<Block<H256>>>>,
+    {
+        if let Some(timestamp) = self.entries.lock().0.get(&hash) {
+            return Ok(*timestamp);
+        }
+
+        let block = fetch().await?.ok_or(Error::BlockNotFound(hash))?;
+        let timestamp = DateTime::from_millis(block.timestamp.low_u64() as i64 * 1000);
+
+        let mut entries = self.entries.lock();
+        let (timestamps, order) = &mut *entries;
+        if timestamps.insert(hash, timestamp).is_none() {
+            order.push_back(hash);
+            while order.len() > self.capacity {
+                if let Some(oldest) = order.pop_front() {
+                    timestamps.remove(&oldest);
+                }
+            }
+        }
+
+        Ok(timestamp)
+    }
+}
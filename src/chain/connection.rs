@@ -1,18 +1,40 @@
-use crate::config::{ChainConfig, RpcEndpoint, RpcType};
+use crate::config::{ChainConfig, FinalityTag, RpcEndpoint, RpcType};
 use crate::metrics::MetricsCollector;
-use crate::circuit_breaker::CircuitBreaker;
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakers};
 use crate::chain::ChainState;
+use crate::chain::quorum::QuorumReader;
 use crate::error::{Error, Result};
+use crate::health::HealthCheck;
 use web3::transports::{WebSocket, Http};
 use web3::Web3;
+use std::future::Future;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use web3::types::{Log, BlockNumber, FilterBuilder, H160};
+use web3::types::{Block, BlockId, BlockNumber, Filter, FilterBuilder, Log, H160, H256, U256, U64};
 use std::time::Duration;
-use std::str::FromStr;
-use futures::Stream;
+use futures::{Stream, StreamExt};
+use dashmap::DashMap;
 
-type EventStream = dyn Stream<Item = web3::Result<Log>> + Send + 'static;
+type EventStream = dyn Stream<Item = Result<StreamItem>> + Send + 'static;
+
+/// Item of a live event stream.
+pub enum StreamItem {
+    Log(Box<Log>),
+    /// Every log up to and including this block has been streamed, whether or
+    /// not any matched.
+    Progress(u64),
+}
+
+/// RPC calls taking longer than this count as failures of the endpoint.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs `call`, failing with `Error::Timeout` after `REQUEST_TIMEOUT`.
+pub async fn with_timeout<R>(call: impl Future<Output = Result<R>>) -> Result<R> {
+    match tokio::time::timeout(REQUEST_TIMEOUT, call).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::Timeout),
+    }
+}
 
 #[derive(Clone)]
 pub enum Transport {
@@ -33,6 +55,82 @@ impl Transport {
             },
         }
     }
+
+    pub async fn block_number(&self) -> Result<U64> {
+        match self {
+            Transport::WebSocket(web3) => web3.eth().block_number().await.map_err(Error::Web3Error),
+            Transport::Http(web3) => web3.eth().block_number().await.map_err(Error::Web3Error),
+        }
+    }
+
+    pub async fn peer_count(&self) -> Result<U256> {
+        match self {
+            Transport::WebSocket(web3) => web3.net().peer_count().await.map_err(Error::Web3Error),
+            Transport::Http(web3) => web3.net().peer_count().await.map_err(Error::Web3Error),
+        }
+    }
+
+    pub async fn block(&self, id: BlockId) -> Result<Option<Block<H256>>> {
+        match self {
+            Transport::WebSocket(web3) => web3.eth().block(id).await.map_err(Error::Web3Error),
+            Transport::Http(web3) => web3.eth().block(id).await.map_err(Error::Web3Error),
+        }
+    }
+
+    pub async fn logs(&self, filter: Filter) -> Result<Vec<Log>> {
+        match self {
+            Transport::WebSocket(web3) => web3.eth().logs(filter).await.map_err(Error::Web3Error),
+            Transport::Http(web3) => web3.eth().logs(filter).await.map_err(Error::Web3Error),
+        }
+    }
+
+    /// Highest block considered final: the `safe`/`finalized` tagged block when
+    /// requested and supported by the node, otherwise `confirmations` below head.
+    pub async fn confirmed_block_number(
+        &self,
+        confirmations: u64,
+        finality: Option<&FinalityTag>,
+    ) -> Result<U64> {
+        if let Some(tag) = finality {
+            let block_number = match tag {
+                FinalityTag::Safe => BlockNumber::Safe,
+                FinalityTag::Finalized => BlockNumber::Finalized,
+            };
+
+            match self.block(BlockId::Number(block_number)).await {
+                Ok(Some(Block { number: Some(number), .. })) => return Ok(number),
+                Ok(_) => tracing::warn!("Node returned no {:?} block, falling back to confirmations", tag),
+                Err(e) => tracing::warn!("Node does not support the {:?} block tag, falling back to confirmations: {}", tag, e),
+            }
+        }
+
+        let head = self.block_number().await?;
+        Ok(head.saturating_sub(confirmations.into()))
+    }
+}
+
+/// Clients of the endpoints of a chain by URL, kept between calls and only
+/// re-created after a failure.
+#[derive(Clone, Default)]
+pub struct TransportCache {
+    clients: Arc<DashMap<String, Transport>>,
+}
+
+impl TransportCache {
+    pub async fn get(&self, endpoint: &RpcEndpoint) -> Result<Transport> {
+        if let Some(transport) = self.clients.get(&endpoint.url) {
+            return Ok(transport.clone());
+        }
+
+        let transport = Transport::new(endpoint).await?;
+        self.clients.insert(endpoint.url.clone(), transport.clone());
+        Ok(transport)
+    }
+
+    /// Drops the client of `url` so that the next call reconnects.
+    pub fn evict(&self, url: &str) {
+        self.clients.remove(url);
+    }
 }
 
 pub struct ChainConnection {
@@ -40,7 +138,9 @@ pub struct ChainConnection {
     pub config: ChainConfig,
     current_endpoint: Arc<RwLock<Option<RpcEndpoint>>>,
     pub state: Arc<ChainState>,
-    pub circuit_breaker: CircuitBreaker,
+    circuit_breakers: CircuitBreakers,
+    health: Arc<HealthCheck>,
+    quorum: Option<Arc<QuorumReader>>,
     polling_interval: Duration,
 }
 
@@ -48,20 +148,21 @@ impl ChainConnection {
     pub async fn new(
         config: ChainConfig,
         metrics: MetricsCollector,
+        health: Arc<HealthCheck>,
+        circuit_breakers: CircuitBreakers,
+        quorum: Option<QuorumReader>,
     ) -> Result<Self> {
         let state = Arc::new(ChainState::new(metrics.clone()));
         
-        let circuit_breaker = CircuitBreaker::new_from_config(
-            config.rpcs[0].circuit_breaker.clone(),
-            metrics.clone(),
-        );
 
         let mut connection = Self {
             transport: None,
             config,
             current_endpoint: Arc::new(RwLock::new(None)),
             state,
-            circuit_breaker,
+            circuit_breakers,
+            health,
+            quorum: quorum.map(Arc::new),
             polling_interval: Duration::from_secs(2),
         };
 
@@ -69,102 +170,345 @@ impl ChainConnection {
         Ok(connection)
     }
 
-    pub async fn subscribe_to_events(&mut self) -> Result<Box<EventStream>> {
+    /// Streams the logs of `contracts` starting at `from_block`, so that the
+    /// caller decides where live indexing resumes instead of the current head.
+    /// With a quorum configured, logs are fetched per block range from every
+    /// endpoint instead of being pushed by a single subscription. Each block
+    /// range fetched in full is reported as `StreamItem::Progress`.
+    pub async fn subscribe_to_events(&mut self, from_block: u64, contracts: Vec<H160>) -> Result<Box<EventStream>> {
         self.ensure_connection().await?;
         
         let transport = self.transport.as_ref().ok_or(Error::NotConnected)?;
-        let contract = H160::from_str(&self.config.contract_address)
-            .map_err(|_| Error::InvalidAddress)?;
         
+        let waits_for_confirmation = self.config.confirmations > 0
+            || self.config.finality.is_some()
+            || self.quorum.is_some();
+
         Ok(match transport {
-            Transport::WebSocket(web3) => {
-                let current_block = web3.eth().block_number().await?;
+            Transport::WebSocket(web3) if !waits_for_confirmation => {
                 let filter = FilterBuilder::default()
-                    .address(vec![contract])
-                    .from_block(BlockNumber::Number(current_block))
+                    .address(contracts.clone())
                     .build();
                 
-                let stream = web3.eth_subscribe().subscribe_logs(filter).await?;
-                Box::new(stream)
-            },
-            Transport::Http(web3) => {
-                let _filter = FilterBuilder::default()
-                    .address(vec![contract])
-                    .build();
+                // Subscribe before reading the head so that no block falls between
+                // the backlog query and the first subscription notification.
+                let stream = with_timeout(async {
+                    web3.eth_subscribe().subscribe_logs(filter).await.map_err(Error::Web3Error)
+                }).await?;
+                let head = with_timeout(transport.block_number()).await?;
 
-                let web3 = web3.clone();
-                let interval = self.polling_interval;
-                
-                let stream = async_stream::stream! {
-                    let mut last_block = web3.eth().block_number().await?;
-                    
-                    loop {
-                        tokio::time::sleep(interval).await;
-                        
-                        let current_block = web3.eth().block_number().await?;
-                        if current_block > last_block {
+                let backlog = {
+                    let transport = transport.clone();
+                    let contracts = contracts.clone();
+                    let max_range = self.backlog_range();
+                    async_stream::stream! {
+                        let mut next_block = from_block;
+                        while next_block <= head.as_u64() {
+                            let to_block = head.as_u64().min(next_block + max_range - 1);
                             let filter = FilterBuilder::default()
-                                .address(vec![contract])
-                                .from_block(BlockNumber::Number(last_block + 1))
-                                .to_block(BlockNumber::Number(current_block))
+                                .address(contracts.clone())
+                                .from_block(BlockNumber::Number(next_block.into()))
+                                .to_block(BlockNumber::Number(to_block.into()))
                                 .build();
-                            
-                            match web3.eth().logs(filter).await {
+
+                            match with_timeout(transport.logs(filter)).await {
                                 Ok(logs) => {
                                     for log in logs {
-                                        yield Ok(log);
+                                        yield Ok(StreamItem::Log(Box::new(log)));
                                     }
+                                    yield Ok(StreamItem::Progress(to_block));
+                                }
+                                Err(e) => {
+                                    yield Err(e);
+                                    return;
                                 }
-                                Err(e) => yield Err(e),
                             }
-                            
-                            last_block = current_block;
+                            next_block = to_block + 1;
                         }
                     }
                 };
-                
-                Box::new(stream)
+
+                let live = stream.filter_map(move |log| async move {
+                    match log {
+                        Ok(log) if !log.is_removed() && log.block_number.is_some_and(|n| n <= head) => None,
+                        log => Some(log.map(|log| StreamItem::Log(Box::new(log))).map_err(Error::Web3Error)),
+                    }
+                });
+
+                Box::new(backlog.chain(live))
+            },
+            Transport::WebSocket(web3) => {
+                let heads = with_timeout(async {
+                    web3.eth_subscribe().subscribe_new_heads().await.map_err(Error::Web3Error)
+                }).await?;
+                let ticks = heads.map(|head| head.map(|_| ()).map_err(Error::Web3Error));
+                Box::new(self.confirmed_log_stream(transport.clone(), contracts, from_block, ticks))
+            },
+            Transport::Http(_) => {
+                let interval = self.polling_interval;
+                let ticks = async_stream::stream! {
+                    loop {
+                        tokio::time::sleep(interval).await;
+                        yield Ok(());
+                    }
+                };
+                Box::new(self.confirmed_log_stream(transport.clone(), contracts, from_block, ticks))
             }
         })
     }
 
+    /// Fetches the logs of every newly confirmed block range, starting at
+    /// `from_block` and then each time `ticks` fires, followed by the range's
+    /// last block as progress. A backlog larger than `backlog_range` blocks is
+    /// fetched in several requests.
+    fn confirmed_log_stream<S>(
+        &self,
+        transport: Transport,
+        contracts: Vec<H160>,
+        from_block: u64,
+        ticks: S,
+    ) -> impl Stream<Item = Result<StreamItem>> + Send + 'static
+    where
+        S: Stream<Item = Result<()>> + Send + 'static,
+    {
+        let confirmations = self.config.confirmations;
+        let finality = self.config.finality.clone();
+        let quorum = self.quorum.clone();
+        let max_range = self.backlog_range();
+
+        async_stream::stream! {
+            futures::pin_mut!(ticks);
+            let mut next_block = U64::from(from_block);
+
+            loop {
+                let current_block = match &quorum {
+                    Some(quorum) => U64::from(quorum.confirmed_block_number(confirmations, finality.as_ref()).await?),
+                    None => with_timeout(transport.confirmed_block_number(confirmations, finality.as_ref())).await?,
+                };
+                if current_block >= next_block {
+                    let to_block = current_block.min(next_block + max_range - 1);
+                    let logs = match &quorum {
+                        Some(quorum) => quorum.logs(next_block.as_u64(), to_block.as_u64(), contracts.clone()).await,
+                        None => {
+                            let filter = FilterBuilder::default()
+                                .address(contracts.clone())
+                                .from_block(BlockNumber::Number(next_block))
+                                .to_block(BlockNumber::Number(to_block))
+                                .build();
+                            with_timeout(transport.logs(filter)).await
+                        }
+                    };
+
+                    match logs {
+                        Ok(logs) => {
+                            for log in logs {
+                                yield Ok(StreamItem::Log(Box::new(log)));
+                            }
+                            yield Ok(StreamItem::Progress(to_block.as_u64()));
+                            next_block = to_block + 1;
+                            if to_block < current_block {
+                                continue;
+                            }
+                        }
+                        Err(e) => yield Err(e),
+                    }
+                }
+
+                match ticks.next().await {
+                    Some(tick) => tick?,
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Largest block range requested at once when catching up on a backlog,
+    /// bounded by the `log_range` settings of the chain.
+    fn backlog_range(&self) -> u64 {
+        let range = &self.config.log_range;
+        range.initial_blocks.min(range.max_blocks).max(range.min_blocks).max(1)
+    }
+
+    /// Highest confirmed block of the connected node, or the one agreed on by a
+    /// quorum of endpoints when one is configured.
+    pub async fn confirmed_block_number(&self) -> Result<u64> {
+        if let Some(quorum) = &self.quorum {
+            return quorum.confirmed_block_number(self.config.confirmations, self.config.finality.as_ref()).await;
+        }
+
+        let transport = self.transport.as_ref().ok_or(Error::NotConnected)?;
+        let block = self
+            .observe(transport.confirmed_block_number(self.config.confirmations, self.config.finality.as_ref()))
+            .await?;
+        Ok(block.as_u64())
+    }
+
+    /// Returns the canonical hash of `number` as currently seen by the connected
+    /// node, or by a quorum of endpoints when one is configured.
+    pub async fn block_hash(&self, number: u64) -> Result<Option<H256>> {
+        if let Some(quorum) = &self.quorum {
+            return quorum.block_hash(number).await;
+        }
+
+        let transport = self.transport.as_ref().ok_or(Error::NotConnected)?;
+        let block = self
+            .observe(transport.block(BlockId::Number(BlockNumber::Number(number.into()))))
+            .await?;
+        Ok(block.and_then(|b| b.hash))
+    }
+
+    pub async fn block_by_hash(&self, hash: H256) -> Result<Option<Block<H256>>> {
+        let transport = self.transport.as_ref().ok_or(Error::NotConnected)?;
+        self.observe(transport.block(BlockId::Hash(hash))).await
+    }
+
+    pub async fn fetch_logs(&self, from_block: u64, to_block: u64, contracts: Vec<H160>) -> Result<Vec<Log>> {
+        if let Some(quorum) = &self.quorum {
+            return quorum.logs(from_block, to_block, contracts).await;
+        }
+
+        let transport = self.transport.as_ref().ok_or(Error::NotConnected)?;
+
+        let filter = FilterBuilder::default()
+            .address(contracts)
+            .from_block(BlockNumber::Number(from_block.into()))
+            .to_block(BlockNumber::Number(to_block.into()))
+            .build();
+
+        self.observe(transport.logs(filter)).await
+    }
+
+    /// Runs a call against the connected endpoint with a permit of its circuit
+    /// breaker and reports the outcome. While the breaker is half-open, calls
+    /// beyond its trial permits are refused instead of being sent.
+    async fn observe<R>(&self, call: impl Future<Output = Result<R>>) -> Result<R> {
+        let permit = match self.current_breaker().await {
+            Some(breaker) => Some(breaker.try_acquire().ok_or(Error::CircuitBreakerOpen)?),
+            None => None,
+        };
+
+        let result = with_timeout(call).await;
+
+        if let Some(permit) = permit {
+            permit.record(&result);
+        }
+
+        result
+    }
+
+    /// Counts a working subscription in favour of the connected endpoint.
+    pub async fn record_success(&self) {
+        if let Some(breaker) = self.current_breaker().await {
+            breaker.record_success();
+        }
+    }
+
+    /// Counts a failed subscription against the connected endpoint.
+    pub async fn record_failure(&self) {
+        if let Some(breaker) = self.current_breaker().await {
+            breaker.record_failure();
+        }
+    }
+
+    async fn current_breaker(&self) -> Option<&CircuitBreaker> {
+        let url = self.current_endpoint_url().await?;
+        self.circuit_breakers.get(&url)
+    }
+
+    /// Whether live listening should move to another endpoint: the current one
+    /// was found unhealthy or its circuit breaker opened, or a higher-priority
+    /// one is available again.
+    pub async fn should_switch_endpoint(&self) -> bool {
+        let current = match self.current_endpoint.read().await.clone() {
+            Some(current) => current,
+            None => return true,
+        };
+
+        let degraded = !self.health.is_healthy(&current.url)
+            || self.circuit_breakers
+                .get(&current.url)
+                .is_some_and(|breaker| !breaker.can_execute());
+
+        let available = self.health.available_endpoints(&self.config.rpcs, &self.circuit_breakers);
+        match self.health.get_best_endpoint(&available) {
+            Some(best) => best.url != current.url && (degraded || best.priority < current.priority),
+            None => false,
+        }
+    }
+
+    pub async fn current_endpoint_url(&self) -> Option<String> {
+        self.current_endpoint.read().await.as_ref().map(|endpoint| endpoint.url.clone())
+    }
+
+    /// Connects to the first candidate endpoint that accepts a connection.
+    /// An endpoint is only tried with a permit of its circuit breaker, so a
+    /// half-open endpoint whose trial calls are taken is skipped; when every
+    /// breaker refuses, the candidates are tried again once one may let calls
+    /// through.
     pub async fn connect(&mut self) -> Result<()> {
         let max_retries = 3;
         let retry_delay = Duration::from_secs(5);
-    
-        for endpoint in &self.config.rpcs {
-            let mut attempts = 0;
-            
-            while attempts < max_retries {
-                tracing::info!("Attempting to connect to {}", endpoint.url);
-                
-                match Transport::new(endpoint).await {
-                    Ok(transport) => {
-                        self.transport = Some(transport);
-                        *self.current_endpoint.write().await = Some(endpoint.clone());
-                        self.state.metrics.set_connection_status(true);
-                        tracing::info!("Successfully connected to {}", endpoint.url);
-                        return Ok(());
-                    }
-                    Err(e) => {
-                        tracing::warn!(
-                            "Failed to connect to {} (attempt {}/{}): {}",
-                            endpoint.url,
-                            attempts + 1,
-                            max_retries,
-                            e
-                        );
-                        self.state.metrics.record_failure();
-                        attempts += 1;
-                        
-                        if attempts < max_retries {
-                            tokio::time::sleep(retry_delay).await;
+
+        loop {
+            let mut attempted = false;
+
+            for endpoint in &self.health.endpoint_candidates(&self.config.rpcs, &self.circuit_breakers) {
+                let mut attempts = 0;
+
+                while attempts < max_retries {
+                    let permit = match self.circuit_breakers.get(&endpoint.url) {
+                        Some(breaker) => match breaker.try_acquire() {
+                            Some(permit) => Some(permit),
+                            None => {
+                                tracing::debug!("Circuit breaker of {} refused the connection", endpoint.url);
+                                break;
+                            }
+                        },
+                        None => None,
+                    };
+                    attempted = true;
+                    tracing::info!("Attempting to connect to {}", endpoint.url);
+
+                    match Transport::new(endpoint).await {
+                        Ok(transport) => {
+                            self.transport = Some(transport);
+                            let previous = self.current_endpoint.write().await.replace(endpoint.clone());
+                            if let Some(previous) = previous.filter(|previous| previous.url != endpoint.url) {
+                                self.state.metrics.for_endpoint(&previous.url).set_connection_status(false);
+                            }
+                            self.state.metrics.for_endpoint(&endpoint.url).set_connection_status(true);
+                            tracing::info!("Successfully connected to {}", endpoint.url);
+                            return Ok(());
+                        }
+                        Err(e) => {
+                            tracing::warn!(
+                                "Failed to connect to {} (attempt {}/{}): {}",
+                                endpoint.url,
+                                attempts + 1,
+                                max_retries,
+                                e
+                            );
+                            self.state.metrics.for_endpoint(&endpoint.url).record_failure();
+                            if let Some(permit) = permit {
+                                permit.record_failure();
+                            }
+                            attempts += 1;
+
+                            if attempts < max_retries {
+                                tokio::time::sleep(retry_delay).await;
+                            }
                         }
                     }
                 }
             }
+
+            if attempted {
+                break;
+            }
+            tracing::warn!("Circuit breakers of every endpoint of {} refuse calls, waiting", self.config.name);
+            tokio::time::sleep(retry_delay).await;
         }
-        
+
         tracing::error!("Failed to connect to any RPC endpoint after all retries");
         Err(Error::NoHealthyEndpoints)
     }
@@ -176,12 +520,7 @@ impl ChainConnection {
 
         match &self.transport {
             Some(transport) => {
-                let block_check = match transport {
-                    Transport::WebSocket(web3) => web3.eth().block_number().await,
-                    Transport::Http(web3) => web3.eth().block_number().await,
-                };
-
-                match block_check {
+                match self.observe(transport.block_number()).await {
                     Ok(_) => Ok(()),
                     Err(_) => {
                         tracing::warn!("Connection check failed, attempting reconnect");
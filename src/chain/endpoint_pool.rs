@@ -0,0 +1,122 @@
+use crate::chain::connection::with_timeout;
+use crate::chain::rate_limiter::RateLimiter;
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakers};
+use crate::config::{ChainConfig, RpcEndpoint, RpcType};
+use crate::error::{Error, Result};
+use crate::health::HealthCheck;
+use crate::metrics::MetricsCollector;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+use web3::transports::Http;
+use web3::Web3;
+
+/// An HTTP endpoint with its own circuit breaker and rate limiter.
+pub struct HttpEndpoint {
+    pub config: RpcEndpoint,
+    web3: Web3<Http>,
+    circuit_breaker: CircuitBreaker,
+    rate_limiter: RateLimiter,
+    metrics: MetricsCollector,
+}
+
+/// The HTTP endpoints of a chain. A request goes to the best endpoint
+/// according to the shared health state whose circuit breaker lets it
+/// through, and fails over to the next candidate when the endpoint cannot be
+/// reached or does not answer within `REQUEST_TIMEOUT`. JSON-RPC errors are
+/// answers from a working node and are returned to the caller as is.
+pub struct HttpEndpointPool {
+    chain_name: String,
+    rpcs: Vec<RpcEndpoint>,
+    endpoints: Vec<HttpEndpoint>,
+    circuit_breakers: CircuitBreakers,
+    health: Arc<HealthCheck>,
+}
+
+impl HttpEndpointPool {
+    pub fn new(config: &ChainConfig, circuit_breakers: &CircuitBreakers, health: Arc<HealthCheck>) -> Result<Self> {
+        let rpcs: Vec<RpcEndpoint> = config.rpcs
+            .iter()
+            .filter(|endpoint| matches!(endpoint.rpc_type, RpcType::Http))
+            .cloned()
+            .collect();
+
+        let mut endpoints = Vec::with_capacity(rpcs.len());
+        for endpoint in &rpcs {
+            let metrics = MetricsCollector::new(&config.name, &endpoint.url);
+            let circuit_breaker = match circuit_breakers.get(&endpoint.url) {
+                Some(breaker) => breaker.clone(),
+                None => CircuitBreaker::new_from_config(endpoint.circuit_breaker.clone(), metrics.clone()),
+            };
+            endpoints.push(HttpEndpoint {
+                config: endpoint.clone(),
+                web3: Web3::new(Http::new(&endpoint.url)?),
+                circuit_breaker,
+                rate_limiter: RateLimiter::new(endpoint.max_requests_per_second),
+                metrics,
+            });
+        }
+
+        Ok(Self {
+            chain_name: config.name.clone(),
+            rpcs,
+            endpoints,
+            circuit_breakers: circuit_breakers.clone(),
+            health,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.endpoints.is_empty()
+    }
+
+    /// Runs `operation` against the candidate endpoints in order until one of
+    /// them answers.
+    pub async fn execute<R, F, Fut>(&self, operation: F) -> Result<R>
+    where
+        F: Fn(Web3<Http>) -> Fut,
+        Fut: Future<Output = web3::Result<R>>,
+    {
+        let mut last_error = None;
+
+        for candidate in self.health.endpoint_candidates(&self.rpcs, &self.circuit_breakers) {
+            let endpoint = match self.endpoints.iter().find(|endpoint| endpoint.config.url == candidate.url) {
+                Some(endpoint) => endpoint,
+                None => continue,
+            };
+            let permit = match endpoint.circuit_breaker.try_acquire() {
+                Some(permit) => permit,
+                None => continue,
+            };
+
+            endpoint.rate_limiter.acquire().await;
+            let start = Instant::now();
+            let result = with_timeout(async { operation(endpoint.web3.clone()).await.map_err(Error::Web3Error) }).await;
+            match result {
+                Ok(value) => {
+                    endpoint.metrics.record_latency(start.elapsed());
+                    permit.record_success();
+                    return Ok(value);
+                }
+                Err(e) if e.is_endpoint_failure() => {
+                    tracing::warn!(
+                        "Request to {} failed on {}, trying the next endpoint: {}",
+                        endpoint.config.url,
+                        self.chain_name,
+                        e
+                    );
+                    endpoint.metrics.record_failure();
+                    permit.record_failure();
+                    last_error = Some(e);
+                }
+                Err(e) => {
+                    // The node answered, so the endpoint itself works.
+                    permit.record_success();
+                    return Err(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or(Error::NoHealthyEndpoints))
+    }
+}
@@ -1,35 +1,87 @@
 use crate::error::{Error, Result};
-use crate::chain::connection::ChainConnection;
-use crate::decoder::abi::EventDecoder;
+use crate::chain::block_timestamps::BlockTimestamps;
+use crate::chain::connection::{ChainConnection, StreamItem};
+use crate::chain::reorg::BlockTracker;
+use crate::db::checkpoint::CheckpointStore;
+use crate::db::contracts::DiscoveredContractStore;
+use crate::db::events::EventStore;
+use crate::db::failed_logs::FailedLogStore;
+use crate::db::write_buffer::WriteBuffer;
+use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+use crate::config::DatabaseConfig;
+use crate::decoder::registry::ContractRegistry;
 use backoff::{ExponentialBackoff, backoff::Backoff};
 use futures::StreamExt;
 use mongodb::Database;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use web3::types::Log;
+use tokio::sync::{Mutex, RwLock};
+use web3::types::{Log, H256};
 use crate::db::models::EventLog;
 
+/// How often live listening checks whether a better endpoint is available and
+/// retries writing buffered events.
+const HOUSEKEEPING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
 pub struct EventListener {
     connection: Arc<RwLock<ChainConnection>>,
-    decoder: EventDecoder,
-    db: Database,
+    contracts: ContractRegistry,
+    events: EventStore,
+    checkpoints: CheckpointStore,
+    discovered: DiscoveredContractStore,
+    failed_logs: FailedLogStore,
+    storage_breaker: CircuitBreaker,
+    write_buffer: WriteBuffer,
+    block_tracker: Mutex<BlockTracker>,
+    block_timestamps: BlockTimestamps,
+    resubscribe: AtomicBool,
+    /// Block of the last saved checkpoint.
+    checkpointed: AtomicU64,
 }
 
 impl EventListener {
-    pub fn new(
+    pub async fn new(
         connection: ChainConnection,
-        decoder: EventDecoder,
+        contracts: ContractRegistry,
         db: Database,
-    ) -> Self {
-        Self {
+        database_config: &DatabaseConfig,
+    ) -> Result<Self> {
+        let block_tracker = Mutex::new(BlockTracker::new(connection.config.max_reorg_depth));
+        let checkpoints = CheckpointStore::new(&db);
+        let storage_breaker = CircuitBreaker::new_from_config(
+            database_config.circuit_breaker.clone(),
+            connection.state.metrics.for_endpoint("mongodb"),
+        );
+        let write_buffer = WriteBuffer::open(
+            &database_config.write_buffer.path,
+            &connection.config.name,
+            database_config.write_buffer.max_events,
+        ).await?;
+        connection.state.metrics.set_write_buffer_events(write_buffer.len().await);
+
+        Ok(Self {
             connection: Arc::new(RwLock::new(connection)),
-            decoder,
-            db,
-        }
+            contracts,
+            events: EventStore::new(&db),
+            checkpoints,
+            discovered: DiscoveredContractStore::new(&db),
+            failed_logs: FailedLogStore::new(&db),
+            storage_breaker,
+            write_buffer,
+            block_tracker,
+            block_timestamps: BlockTimestamps::default(),
+            resubscribe: AtomicBool::new(false),
+            checkpointed: AtomicU64::new(0),
+        })
     }
 
-    pub async fn start(&self) -> Result<()> {
+    /// Listens for events from `from_block` onwards, resuming after the last
+    /// processed block whenever the subscription has to be re-established.
+    pub async fn start(&self, from_block: u64) -> Result<()> {
+        self.connection.read().await.state.update_block(from_block.saturating_sub(1)).await;
+        self.flush_write_buffer().await;
+
         loop {
             match self.listen_events().await {
                 Ok(_) => {},
@@ -42,6 +94,21 @@ impl EventListener {
         }
     }
 
+    /// Highest confirmed block, moving to another endpoint when the connected
+    /// one fails or its circuit breaker refuses the call.
+    pub async fn confirmed_block_number(&self) -> Result<u64> {
+        let result = self.connection.read().await.confirmed_block_number().await;
+        match result {
+            Err(e) if e.is_endpoint_failure() || matches!(e, Error::CircuitBreakerOpen) => {
+                tracing::warn!("Failed to read the confirmed head, reconnecting: {:?}", e);
+                let mut connection = self.connection.write().await;
+                connection.connect().await?;
+                connection.confirmed_block_number().await
+            }
+            result => result,
+        }
+    }
+
     async fn process_event(&self, log: Log) -> Result<String> {
         let start_time = std::time::Instant::now();
         let connection = self.connection.read().await;
@@ -49,12 +116,7 @@ impl EventListener {
 
         tracing::info!("Processing event from transaction: {:?}", log.transaction_hash);
 
-        let raw_log = ethabi::RawLog {
-            topics: log.topics.clone(),
-            data: log.data.0.clone(),
-        };
-
-        let (event_name, params) = self.decoder.decode_log(raw_log)?;
+        let (event_name, params) = self.contracts.decode_log(&log)?;
         tracing::info!(
             "Successfully decoded event: {}\nParameters: {:?}",
             event_name,
@@ -67,45 +129,287 @@ impl EventListener {
             connection.state.metrics.update_block_height(block_number.as_u64());
         }
 
-        let event_log = EventLog {
-            chain_name: connection.config.name.clone(),
-            event_name: event_name.clone(),
-            block_number: log.block_number.unwrap_or_default().as_u64(),
-            transaction_hash: format!("{:?}", log.transaction_hash.unwrap_or_default()),
-            params,
-            timestamp: mongodb::bson::DateTime::now(),
-        };
+        if let Some(child) = self.contracts.discover_child(&log, &event_name, &params) {
+            self.discovered.save(&connection.config.name, &child).await?;
+            // The new address is only part of the log filter once the stream is
+            // re-created, which replays the current block onwards.
+            self.resubscribe.store(true, Ordering::SeqCst);
+        }
 
-        if connection.circuit_breaker.can_execute() {
-            let event_log_clone = event_log.clone();
-            match backoff::future::retry(ExponentialBackoff::default(), || async {
-                match self.db.collection::<EventLog>("events")
-                    .insert_one(event_log_clone.clone(), None)
-                    .await
-                {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(backoff::Error::Permanent(Error::MongoError(e)))
-                }
-            }).await {
-                Ok(_) => {
-                    tracing::info!(
-                        "Successfully stored event {} from tx {:?}",
-                        event_name,
-                        log.transaction_hash
-                    );
-                    connection.circuit_breaker.record_success();
+        let block_hash = log.block_hash.unwrap_or_default();
+        let block_timestamp = self.block_timestamps
+            .get_or_fetch(block_hash, || connection.block_by_hash(block_hash))
+            .await?;
+
+        let event_log = EventLog::new(&connection.config.name, &event_name, params, &log, block_timestamp);
+
+        self.store(&event_log).await?;
+        tracing::info!(
+            "Successfully stored event {} from tx {:?}",
+            event_name,
+            log.transaction_hash
+        );
+
+        let duration = start_time.elapsed().as_secs_f64();
+        connection.state.metrics.observe_event_processing_time(&event_name, duration);
+
+        Ok(event_name)
+    }
+
+    /// Writes `event` to MongoDB, or spools it to the write buffer while the
+    /// storage circuit breaker is open. Once events are buffered, new ones
+    /// queue behind them so that writes reach the database in order.
+    async fn store(&self, event: &EventLog) -> Result<()> {
+        self.flush_write_buffer().await;
+
+        if self.write_buffer.is_empty().await {
+            if let Some(permit) = self.storage_breaker.try_acquire() {
+                match self.events.upsert(event).await {
+                    Ok(()) => {
+                        permit.record_success();
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        permit.record_failure();
+                        tracing::warn!("Failed to store event in MongoDB, buffering it: {:?}", e);
+                    }
                 }
-                Err(e) => {
-                    tracing::error!("Failed to store event in MongoDB: {:?}", e);
-                    return Err(Error::StorageError(format!("Failed to store event: {:?}", e)));
+            }
+        }
+
+        let connection = self.connection.read().await;
+        self.write_buffer.push(event).await?;
+        connection.state.metrics.set_write_buffer_events(self.write_buffer.len().await);
+        Ok(())
+    }
+
+    /// Writes the buffered events back to MongoDB, in order, when the storage
+    /// circuit breaker lets calls through.
+    async fn flush_write_buffer(&self) {
+        if self.write_buffer.is_empty().await {
+            return;
+        }
+        let permit = match self.storage_breaker.try_acquire() {
+            Some(permit) => permit,
+            None => return,
+        };
+
+        let connection = self.connection.read().await;
+        match self.write_buffer.flush(&self.events).await {
+            Ok(flushed) => {
+                permit.record_success();
+                tracing::info!(
+                    "Flushed {} buffered events to MongoDB for chain {}",
+                    flushed,
+                    connection.config.name
+                );
+            }
+            Err(e) => {
+                permit.record_failure();
+                tracing::warn!("Failed to flush write buffer for chain {}: {:?}", connection.config.name, e);
+            }
+        }
+        connection.state.metrics.set_write_buffer_events(self.write_buffer.len().await);
+    }
+
+    /// Drops the buffered events for which `keep` returns false.
+    async fn discard_buffered(&self, keep: impl Fn(&EventLog) -> bool) -> Result<usize> {
+        let dropped = self.write_buffer.retain(keep).await?;
+        if dropped > 0 {
+            let connection = self.connection.read().await;
+            connection.state.metrics.set_write_buffer_events(self.write_buffer.len().await);
+        }
+        Ok(dropped)
+    }
+
+    /// Stores `log` and remembers the hash of the block it belongs to. The
+    /// first log of a new block marks the previous tracked block as complete.
+    async fn handle_log(&self, log: Log) -> Result<String> {
+        let block = log.block_number.zip(log.block_hash);
+
+        if let Some((number, _)) = block {
+            let completed = self.block_tracker.lock().await.latest();
+            let checkpointed = self.checkpointed.load(Ordering::SeqCst);
+            if let Some((completed, completed_hash)) = completed
+                .filter(|(n, _)| *n < number.as_u64() && *n > checkpointed)
+            {
+                self.save_checkpoint(completed, completed_hash).await;
+            }
+        }
+
+        let result = self.process_event(log).await;
+
+        if let Some((number, hash)) = block {
+            self.block_tracker.lock().await.record(number.as_u64(), hash);
+        }
+
+        result
+    }
+
+    async fn dead_letter(&self, log: &Log, error: &Error) {
+        let connection = self.connection.read().await;
+        if let Err(e) = self.failed_logs.record(&connection.config.name, log, error).await {
+            tracing::error!("Failed to record failed log of tx {:?}: {:?}", log.transaction_hash, e);
+        }
+    }
+
+    async fn save_checkpoint(&self, block_number: u64, block_hash: H256) {
+        let connection = self.connection.read().await;
+        if let Err(e) = self.checkpoints
+            .save_all(&connection.config.name, &self.contracts.addresses(), block_number, block_hash)
+            .await
+        {
+            tracing::error!("Failed to save checkpoint at block {}: {:?}", block_number, e);
+            return;
+        }
+        self.checkpointed.store(block_number, Ordering::SeqCst);
+    }
+
+    /// Checkpoints `block`, reported complete by the stream, so that a quiet
+    /// contract does not re-scan from its last event. Returns false when the
+    /// block's hash could not be read.
+    async fn checkpoint_progress(&self, block: u64) -> bool {
+        if block <= self.checkpointed.load(Ordering::SeqCst) {
+            return true;
+        }
+
+        let hash = self.connection.read().await.block_hash(block).await;
+        match hash {
+            Ok(Some(hash)) => {
+                self.save_checkpoint(block, hash).await;
+                true
+            }
+            Ok(None) => {
+                tracing::warn!("Block {} to checkpoint was not found", block);
+                false
+            }
+            Err(e) => {
+                tracing::warn!("Failed to read the hash of block {} to checkpoint: {:?}", block, e);
+                false
+            }
+        }
+    }
+
+    /// Compares the block of `log` against the tracked block hashes and, when
+    /// the chain has been reorganized, returns the last block both forks share.
+    async fn detect_reorg(&self, log: &Log) -> Result<Option<u64>> {
+        let (number, hash) = match (log.block_number, log.block_hash) {
+            (Some(number), Some(hash)) => (number.as_u64(), hash),
+            _ => return Ok(None),
+        };
+
+        let connection = self.connection.read().await;
+        let tracker = self.block_tracker.lock().await;
+
+        let diverged = match tracker.hash_at(number) {
+            Some(known) => known != hash,
+            None => match tracker.latest() {
+                Some((latest, latest_hash)) if latest < number => connection
+                    .block_hash(latest)
+                    .await?
+                    .map(|canonical| canonical != latest_hash)
+                    .unwrap_or(false),
+                _ => false,
+            },
+        };
+
+        if !diverged {
+            return Ok(None);
+        }
+
+        for (block, known) in tracker.blocks_below(number) {
+            if connection.block_hash(block).await? == Some(known) {
+                return Ok(Some(block));
+            }
+        }
+
+        let oldest = tracker.oldest().unwrap_or(number);
+        tracing::warn!(
+            "Reorg on chain {} is deeper than the {} tracked blocks, rolling back from block {}",
+            connection.config.name,
+            connection.config.max_reorg_depth,
+            oldest
+        );
+        Ok(Some(oldest.saturating_sub(1)))
+    }
+
+    /// Removes every event above `ancestor` and re-indexes the canonical blocks
+    /// up to (but excluding) `reorg_block`, which is processed by the caller.
+    async fn rollback(&self, ancestor: u64, reorg_block: u64) -> Result<()> {
+        let connection = self.connection.read().await;
+
+        tracing::warn!(
+            "Chain reorganization detected on {} at block {}, rolling back to block {}",
+            connection.config.name,
+            reorg_block,
+            ancestor
+        );
+
+        let removed = self.events
+            .delete_after_block(&connection.config.name, ancestor)
+            .await?
+            + self.discard_buffered(|event| event.block_number <= ancestor).await? as u64;
+
+        connection.state.metrics.record_reorg();
+        connection.state.metrics.record_events_removed(removed);
+        self.block_tracker.lock().await.rollback(ancestor + 1);
+        // A failed re-index then resumes right after the common ancestor.
+        connection.state.update_block(ancestor).await;
+
+        if let Some(ancestor_hash) = connection.block_hash(ancestor).await? {
+            self.save_checkpoint(ancestor, ancestor_hash).await;
+        }
+
+        if reorg_block > ancestor + 1 {
+            let logs = connection.fetch_logs(ancestor + 1, reorg_block - 1, self.contracts.addresses()).await?;
+            tracing::info!(
+                "Re-indexing {} canonical events between blocks {} and {} for chain {}",
+                logs.len(),
+                ancestor + 1,
+                reorg_block - 1,
+                connection.config.name
+            );
+            for log in logs {
+                match self.handle_log(log.clone()).await {
+                    Ok(_) => {}
+                    Err(e) if e.is_decode_failure() => {
+                        tracing::error!("Failed to re-index event after reorg: {:?}", e);
+                        self.dead_letter(&log, &e).await;
+                    }
+                    Err(e) => return Err(e),
                 }
             }
         }
 
-        let duration = start_time.elapsed().as_secs_f64();
-        connection.state.metrics.observe_event_processing_time(&event_name, duration);
+        Ok(())
+    }
 
-        Ok(event_name)
+    /// Deletes the events of a log the node reported as removed from the canonical chain.
+    async fn remove_event(&self, log: &Log) -> Result<()> {
+        let connection = self.connection.read().await;
+        let block_number = log.block_number.unwrap_or_default().as_u64();
+
+        let transaction_hash = format!("{:?}", log.transaction_hash.unwrap_or_default());
+        let log_index = log.log_index.unwrap_or_default().as_u64();
+        let removed = self.events.delete_log(&connection.config.name, log).await?
+            + self.discard_buffered(|event| event.transaction_hash != transaction_hash || event.log_index != log_index).await? as u64;
+
+        tracing::warn!(
+            "Removed {} event(s) of tx {:?} at log index {:?} in block {} on chain {} after reorg",
+            removed,
+            log.transaction_hash,
+            log.log_index,
+            block_number,
+            connection.config.name
+        );
+        connection.state.metrics.record_events_removed(removed);
+
+        let mut tracker = self.block_tracker.lock().await;
+        if log.block_hash.is_some() && tracker.hash_at(block_number) == log.block_hash {
+            tracker.rollback(block_number);
+        }
+
+        Ok(())
     }
 
     async fn listen_events(&self) -> Result<()> {
@@ -116,59 +420,188 @@ impl EventListener {
             ..ExponentialBackoff::default()
         };
 
-        let mut last_block = 0u64;
-
         loop {
             let mut connection = self.connection.write().await;
-            match connection.subscribe_to_events().await {
+            let from_block = *connection.state.last_processed_block.read().await + 1;
+            match connection.subscribe_to_events(from_block, self.contracts.addresses()).await {
                 Ok(event_stream) => {
+                    connection.record_success().await;
                     drop(connection);
                     backoff.reset();
                     
                     let mut pinned_stream = Pin::from(event_stream);
-                    tracing::info!("Starting to process events for chain {}", self.connection.read().await.config.name);
+                    tracing::info!(
+                        "Starting to process events for chain {} from block {}",
+                        self.connection.read().await.config.name,
+                        from_block
+                    );
                     
-                    while let Some(result) = pinned_stream.next().await {
+                    let mut storage_changes = self.storage_breaker.subscribe();
+                    let mut housekeeping = tokio::time::interval(HOUSEKEEPING_INTERVAL);
+                    housekeeping.tick().await;
+                    let mut failover = false;
+                    let mut buffer_full = false;
+                    let mut progress = None;
+
+                    loop {
+                        let result = tokio::select! {
+                            result = pinned_stream.next() => match result {
+                                Some(result) => result,
+                                None => break,
+                            },
+                            Ok(change) = storage_changes.recv() => {
+                                // MongoDB may be back: write the buffered events
+                                // without waiting for the next tick.
+                                if matches!(change.to, CircuitState::HalfOpen(_)) {
+                                    self.flush_write_buffer().await;
+                                }
+                                continue;
+                            }
+                            _ = housekeeping.tick() => {
+                                self.flush_write_buffer().await;
+                                if let Some(block) = progress.take() {
+                                    if !self.checkpoint_progress(block).await {
+                                        progress = Some(block);
+                                    }
+                                }
+                                if self.connection.read().await.should_switch_endpoint().await {
+                                    failover = true;
+                                    break;
+                                }
+                                continue;
+                            }
+                        };
+
                         let connection = self.connection.read().await;
-                        connection.state.metrics.record_event_received();
 
-                        match result.map_err(Error::Web3Error) {
-                            Ok(log) => {
+                        match result {
+                            Ok(StreamItem::Progress(block)) => {
+                                if block > *connection.state.last_processed_block.read().await {
+                                    connection.state.update_block(block).await;
+                                    progress = Some(block);
+                                }
+                            }
+                            Ok(StreamItem::Log(log)) if log.is_removed() => {
+                                connection.state.metrics.record_event_received();
+                                if let Err(e) = self.remove_event(&log).await {
+                                    tracing::error!("Failed to remove reorged event: {:?}", e);
+                                }
+                            }
+                            Ok(StreamItem::Log(log)) => {
+                                let log = *log;
+                                connection.state.metrics.record_event_received();
+                                match self.detect_reorg(&log).await {
+                                    Ok(Some(ancestor)) => {
+                                        progress = None;
+                                        let reorg_block = log.block_number.unwrap_or_default().as_u64();
+                                        if let Err(e) = self.rollback(ancestor, reorg_block).await {
+                                            tracing::error!("Failed to roll back reorged blocks: {:?}", e);
+                                            break;
+                                        }
+                                    }
+                                    Ok(None) => {}
+                                    Err(e) => tracing::warn!("Failed to check for reorg: {:?}", e),
+                                }
                                 if let Some(block_number) = log.block_number {
                                     let current_block = block_number.as_u64();
-                                    if current_block > last_block {
+                                    // Only the blocks before the current one are known to be complete,
+                                    // so a resubscription replays the current block; upserts make that safe.
+                                    if current_block > *connection.state.last_processed_block.read().await + 1 {
                                         tracing::info!(
                                             "Processing block #{} for chain {}",
                                             current_block,
                                             connection.config.name
                                         );
-                                        last_block = current_block;
+                                        connection.state.update_block(current_block - 1).await;
                                     }
-                                    connection.state.metrics.update_block_height(current_block);
                                 }
-                                match self.process_event(log).await {
+                                match self.handle_log(log.clone()).await {
                                     Ok(event_name) => {
                                         connection.state.metrics.record_event_by_type(&event_name);
                                         connection.state.metrics.record_event_processed(&event_name);
                                     }
-                                    Err(e) => {
-                                        connection.state.metrics.record_event_decode_failure("decode_error");
+                                    Err(e @ Error::WriteBufferFull(_)) => {
+                                        // Nothing was stored: stop consuming so that the block
+                                        // is replayed once MongoDB accepts writes again.
+                                        tracing::error!(
+                                            "{}, pausing chain {} until MongoDB is back",
+                                            e,
+                                            connection.config.name
+                                        );
+                                        buffer_full = true;
+                                        break;
+                                    }
+                                    Err(e) if e.is_decode_failure() => {
+                                        connection.state.metrics.record_event_decode_failure(e.decode_failure_reason());
                                         tracing::error!("Failed to process event: {:?}", e);
+                                        self.dead_letter(&log, &e).await;
                                     }
+                                    Err(e) => {
+                                        // The log is fine but could not be stored yet: stop consuming
+                                        // so that its block is replayed, from another endpoint when
+                                        // the current one is failing.
+                                        tracing::error!(
+                                            "Failed to process event on chain {}, replaying block {:?}: {:?}",
+                                            connection.config.name,
+                                            log.block_number,
+                                            e
+                                        );
+                                        failover = e.is_endpoint_failure()
+                                            || matches!(e, Error::CircuitBreakerOpen | Error::BlockNotFound(_));
+                                        break;
+                                    }
+                                }
+
+                                if self.resubscribe.swap(false, Ordering::SeqCst) {
+                                    tracing::info!(
+                                        "Contract set changed on chain {}, resubscribing",
+                                        connection.config.name
+                                    );
+                                    break;
                                 }
                             }
                             Err(e) => {
                                 tracing::error!("Event stream error: {:?}", e);
+                                if e.is_endpoint_failure() {
+                                    connection.record_failure().await;
+                                }
                                 break;
                             }
                         }
                     }
 
+                    drop(pinned_stream);
+                    if let Some(block) = progress {
+                        self.checkpoint_progress(block).await;
+                    }
+
+                    if buffer_full {
+                        while self.write_buffer.is_full().await {
+                            tokio::time::sleep(HOUSEKEEPING_INTERVAL).await;
+                            self.flush_write_buffer().await;
+                        }
+                        continue;
+                    }
+
+                    if failover {
+                        let mut connection = self.connection.write().await;
+                        tracing::info!(
+                            "Switching chain {} away from endpoint {:?}",
+                            connection.config.name,
+                            connection.current_endpoint_url().await
+                        );
+                        connection.connect().await?;
+                        continue;
+                    }
+
                     tracing::warn!("Event stream ended, attempting to resubscribe...");
                     tokio::time::sleep(std::time::Duration::from_secs(5)).await;
                 }
                 Err(e) => {
                     tracing::error!("Failed to create event stream: {:?}", e);
+                    if e.is_endpoint_failure() {
+                        connection.record_failure().await;
+                    }
                     if let Some(duration) = backoff.next_backoff() {
                         tracing::info!("Waiting {:?} before retry", duration);
                         tokio::time::sleep(duration).await;
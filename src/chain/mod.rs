@@ -1,5 +1,10 @@
+pub mod block_timestamps;
 pub mod connection;
+pub mod endpoint_pool;
 pub mod event_listener;
+pub mod quorum;
+pub mod rate_limiter;
+pub mod reorg;
 
 use crate::metrics::MetricsCollector;
 use std::sync::Arc;
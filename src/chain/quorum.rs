@@ -0,0 +1,393 @@
+use crate::chain::connection::{with_timeout, Transport, TransportCache};
+use crate::circuit_breaker::{CircuitBreakers, Permit};
+use crate::config::{ChainConfig, FinalityTag, QuorumConfig, RpcEndpoint};
+use crate::db::diagnostics::QuorumDiagnosticsStore;
+use crate::db::models::{QuorumAnswer, QuorumDisagreement};
+use crate::error::{Error, Result};
+use crate::metrics::MetricsCollector;
+use mongodb::bson::DateTime;
+use mongodb::Database;
+use std::future::Future;
+use web3::types::{BlockId, BlockNumber, Bytes, FilterBuilder, Log, H160, H256, U256};
+
+/// Logs listed per answer in the `quorum_disagreements` collection.
+const MAX_DESCRIBED_LOGS: usize = 50;
+
+/// The fields of a log every provider must agree on. Fields such as `removed`
+/// or `log_type` are left out as providers fill them in differently.
+#[derive(PartialEq)]
+struct LogIdentity {
+    block_hash: Option<H256>,
+    transaction_hash: Option<H256>,
+    log_index: Option<U256>,
+    address: H160,
+    topics: Vec<H256>,
+    data: Bytes,
+}
+
+impl From<&Log> for LogIdentity {
+    fn from(log: &Log) -> Self {
+        Self {
+            block_hash: log.block_hash,
+            transaction_hash: log.transaction_hash,
+            log_index: log.log_index,
+            address: log.address,
+            topics: log.topics.clone(),
+            data: log.data.clone(),
+        }
+    }
+}
+
+/// Sends the same read to every endpoint of a chain whose circuit breaker
+/// lets it through and returns the answer given by at least `min_agreement`
+/// of them. Reads on which the endpoints disagree are counted and recorded in
+/// the `quorum_disagreements` collection.
+pub struct QuorumReader {
+    chain_name: String,
+    min_agreement: usize,
+    endpoints: Vec<RpcEndpoint>,
+    clients: TransportCache,
+    circuit_breakers: CircuitBreakers,
+    diagnostics: QuorumDiagnosticsStore,
+    metrics: MetricsCollector,
+}
+
+impl QuorumReader {
+    pub fn new(
+        config: &ChainConfig,
+        quorum: &QuorumConfig,
+        circuit_breakers: CircuitBreakers,
+        db: &Database,
+        metrics: MetricsCollector,
+    ) -> Result<Self> {
+        if quorum.min_agreement < 2 || quorum.min_agreement > config.rpcs.len() {
+            return Err(Error::InvalidConfig(format!(
+                "Quorum of chain {} needs between 2 and {} agreeing endpoints, got {}",
+                config.name,
+                config.rpcs.len(),
+                quorum.min_agreement
+            )));
+        }
+
+        Ok(Self {
+            chain_name: config.name.clone(),
+            min_agreement: quorum.min_agreement,
+            endpoints: config.rpcs.clone(),
+            clients: TransportCache::default(),
+            circuit_breakers,
+            diagnostics: QuorumDiagnosticsStore::new(db),
+            metrics,
+        })
+    }
+
+    pub async fn logs(&self, from_block: u64, to_block: u64, contracts: Vec<H160>) -> Result<Vec<Log>> {
+        let filter = FilterBuilder::default()
+            .address(contracts)
+            .from_block(BlockNumber::Number(from_block.into()))
+            .to_block(BlockNumber::Number(to_block.into()))
+            .build();
+
+        self.agree(
+            "logs",
+            format!("logs of blocks {} to {}", from_block, to_block),
+            |transport| {
+                let filter = filter.clone();
+                async move { transport.logs(filter).await }
+            },
+            |logs| logs.iter().map(LogIdentity::from).collect::<Vec<_>>(),
+            |logs| describe_logs(logs),
+        )
+        .await
+    }
+
+    pub async fn block_hash(&self, number: u64) -> Result<Option<H256>> {
+        self.agree(
+            "block_hash",
+            format!("hash of block {}", number),
+            |transport| async move {
+                let block = transport.block(BlockId::Number(BlockNumber::Number(number.into()))).await?;
+                Ok(block.and_then(|b| b.hash))
+            },
+            |hash| *hash,
+            |hash| match hash {
+                Some(hash) => format!("{:?}", hash),
+                None => "block not found".to_string(),
+            },
+        )
+        .await
+    }
+
+    /// Highest confirmed block reached by at least `min_agreement` endpoints,
+    /// so that a lagging or lying endpoint cannot set the range queried next.
+    pub async fn confirmed_block_number(&self, confirmations: u64, finality: Option<&FinalityTag>) -> Result<u64> {
+        let answers = self
+            .call_all(|transport| {
+                let finality = finality.cloned();
+                async move { transport.confirmed_block_number(confirmations, finality.as_ref()).await }
+            })
+            .await;
+
+        let heads = answers
+            .iter()
+            .filter_map(|(_, result)| result.as_ref().ok().map(|head| head.as_u64()))
+            .collect();
+
+        match agreed_minimum(heads, self.min_agreement) {
+            Some(head) => Ok(head),
+            None => {
+                let query = "confirmed head".to_string();
+                let answers = answers
+                    .into_iter()
+                    .map(|(url, result)| QuorumAnswer {
+                        endpoints: vec![url],
+                        answer: match result {
+                            Ok(head) => head.to_string(),
+                            Err(e) => format!("error: {}", e),
+                        },
+                    })
+                    .collect();
+                self.record_disagreement("confirmed_block_number", &query, false, answers).await;
+                Err(Error::QuorumNotReached(query))
+            }
+        }
+    }
+
+    /// Runs `call` on every available endpoint and picks the answer agreed on
+    /// as described in [`tally`]. Disagreements are recorded.
+    async fn agree<R, K, F, Fut>(
+        &self,
+        kind: &str,
+        query: String,
+        call: F,
+        identity: impl Fn(&R) -> K,
+        describe: impl Fn(&R) -> String,
+    ) -> Result<R>
+    where
+        F: Fn(Transport) -> Fut,
+        Fut: Future<Output = Result<R>>,
+        K: PartialEq,
+    {
+        let tally = tally(self.call_all(call).await, self.min_agreement, identity);
+
+        if tally.groups.len() > 1 || !tally.reached {
+            let answers = tally
+                .groups
+                .iter()
+                .map(|(answer, urls)| QuorumAnswer {
+                    endpoints: urls.clone(),
+                    answer: describe(answer),
+                })
+                .chain(tally.errors)
+                .collect();
+            self.record_disagreement(kind, &query, tally.reached, answers).await;
+        }
+
+        match tally.groups.into_iter().next() {
+            Some((answer, _)) if tally.reached => Ok(answer),
+            _ => Err(Error::QuorumNotReached(query)),
+        }
+    }
+
+    /// Runs `call` on every available endpoint, returning each answer with the
+    /// URL of the endpoint that gave it.
+    async fn call_all<R, F, Fut>(&self, call: F) -> Vec<(String, Result<R>)>
+    where
+        F: Fn(Transport) -> Fut,
+        Fut: Future<Output = Result<R>>,
+    {
+        let available = self.available_endpoints();
+        let urls: Vec<String> = available.iter().map(|(endpoint, _)| endpoint.url.clone()).collect();
+        let results = futures::future::join_all(
+            available.into_iter().map(|(endpoint, permit)| self.call(endpoint, permit, &call)),
+        )
+        .await;
+
+        urls.into_iter().zip(results).collect()
+    }
+
+    /// Endpoints taking part in the next read, each with a permit of its
+    /// circuit breaker. Every one of them is called.
+    fn available_endpoints(&self) -> Vec<(&RpcEndpoint, Option<Permit>)> {
+        self.endpoints
+            .iter()
+            .filter_map(|endpoint| match self.circuit_breakers.get(&endpoint.url) {
+                Some(breaker) => breaker.try_acquire().map(|permit| (endpoint, Some(permit))),
+                None => Some((endpoint, None)),
+            })
+            .collect()
+    }
+
+    async fn call<R, F, Fut>(&self, endpoint: &RpcEndpoint, permit: Option<Permit>, call: &F) -> Result<R>
+    where
+        F: Fn(Transport) -> Fut,
+        Fut: Future<Output = Result<R>>,
+    {
+        let result = match self.clients.get(endpoint).await {
+            Ok(transport) => with_timeout(call(transport)).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = &result {
+            if e.is_endpoint_failure() {
+                tracing::warn!("Cross-checked read on {} failed: {}", endpoint.url, e);
+                self.clients.evict(&endpoint.url);
+                self.metrics.for_endpoint(&endpoint.url).record_failure();
+            }
+        }
+        if let Some(permit) = permit {
+            permit.record(&result);
+        }
+
+        result
+    }
+
+    async fn record_disagreement(&self, kind: &str, query: &str, quorum_reached: bool, answers: Vec<QuorumAnswer>) {
+        self.metrics.record_quorum_disagreement(kind);
+        if quorum_reached {
+            tracing::warn!("Endpoints of {} disagree on {}: {:?}", self.chain_name, query, answers);
+        } else {
+            tracing::error!("No quorum of endpoints of {} agreed on {}: {:?}", self.chain_name, query, answers);
+            self.metrics.record_quorum_failure(kind);
+        }
+
+        let disagreement = QuorumDisagreement {
+            chain_name: self.chain_name.clone(),
+            query: query.to_string(),
+            quorum_reached,
+            answers,
+            recorded_at: DateTime::now(),
+        };
+        if let Err(e) = self.diagnostics.record(&disagreement).await {
+            tracing::error!("Failed to record quorum disagreement on {}: {:?}", query, e);
+        }
+    }
+}
+
+/// Answers of a cross-checked read grouped by identity, largest group first.
+struct Tally<R> {
+    groups: Vec<(R, Vec<String>)>,
+    errors: Vec<QuorumAnswer>,
+    reached: bool,
+}
+
+/// Groups `answers` by `identity`. The largest group wins when it has at least
+/// `min_agreement` endpoints and no other group is as large.
+fn tally<R, K: PartialEq>(
+    answers: Vec<(String, Result<R>)>,
+    min_agreement: usize,
+    identity: impl Fn(&R) -> K,
+) -> Tally<R> {
+    let mut groups: Vec<(K, R, Vec<String>)> = Vec::new();
+    let mut errors = Vec::new();
+    for (url, result) in answers {
+        match result {
+            Ok(answer) => {
+                let key = identity(&answer);
+                match groups.iter_mut().find(|(other, _, _)| *other == key) {
+                    Some((_, _, urls)) => urls.push(url),
+                    None => groups.push((key, answer, vec![url])),
+                }
+            }
+            Err(e) => errors.push(QuorumAnswer {
+                endpoints: vec![url],
+                answer: format!("error: {}", e),
+            }),
+        }
+    }
+    groups.sort_by_key(|(_, _, urls)| std::cmp::Reverse(urls.len()));
+
+    let reached = match groups.as_slice() {
+        [(_, _, first), rest @ ..] => {
+            first.len() >= min_agreement && rest.first().is_none_or(|(_, _, second)| second.len() < first.len())
+        }
+        [] => false,
+    };
+
+    Tally {
+        groups: groups.into_iter().map(|(_, answer, urls)| (answer, urls)).collect(),
+        errors,
+        reached,
+    }
+}
+
+/// Highest of `heads` reached by at least `min_agreement` of them.
+fn agreed_minimum(mut heads: Vec<u64>, min_agreement: usize) -> Option<u64> {
+    heads.sort_unstable_by(|a, b| b.cmp(a));
+    heads.get(min_agreement.checked_sub(1)?).copied()
+}
+
+fn describe_logs(logs: &[Log]) -> String {
+    let mut described: Vec<String> = logs
+        .iter()
+        .take(MAX_DESCRIBED_LOGS)
+        .map(|log| {
+            format!(
+                "{:?}:{}@{:?}",
+                log.transaction_hash.unwrap_or_default(),
+                log.log_index.unwrap_or_default(),
+                log.block_hash.unwrap_or_default()
+            )
+        })
+        .collect();
+    if logs.len() > MAX_DESCRIBED_LOGS {
+        described.push(format!("and {} more", logs.len() - MAX_DESCRIBED_LOGS));
+    }
+
+    format!("{} logs [{}]", logs.len(), described.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answers(results: Vec<Result<u64>>) -> Vec<(String, Result<u64>)> {
+        results
+            .into_iter()
+            .enumerate()
+            .map(|(i, result)| (format!("http://rpc-{}", i), result))
+            .collect()
+    }
+
+    #[test]
+    fn tally_picks_the_answer_of_the_majority() {
+        let tally = tally(answers(vec![Ok(1), Ok(2), Ok(1), Err(Error::Timeout)]), 2, |answer| *answer);
+
+        assert!(tally.reached);
+        assert_eq!(tally.groups[0].0, 1);
+        assert_eq!(tally.groups[0].1, vec!["http://rpc-0", "http://rpc-2"]);
+        assert_eq!(tally.groups.len(), 2);
+        assert_eq!(tally.errors.len(), 1);
+    }
+
+    #[test]
+    fn tally_rejects_a_tie() {
+        let tally = tally(answers(vec![Ok(1), Ok(2), Ok(1), Ok(2)]), 2, |answer| *answer);
+
+        assert!(!tally.reached);
+        assert_eq!(tally.groups.len(), 2);
+    }
+
+    #[test]
+    fn tally_rejects_a_split_below_min_agreement() {
+        let tally = tally(answers(vec![Ok(1), Ok(2), Ok(3)]), 2, |answer| *answer);
+
+        assert!(!tally.reached);
+        assert_eq!(tally.groups.len(), 3);
+    }
+
+    #[test]
+    fn tally_rejects_all_errors() {
+        let tally = tally(answers(vec![Err(Error::Timeout), Err(Error::NotConnected)]), 2, |answer| *answer);
+
+        assert!(!tally.reached);
+        assert!(tally.groups.is_empty());
+        assert_eq!(tally.errors.len(), 2);
+    }
+
+    #[test]
+    fn agreed_minimum_ignores_heads_ahead_of_the_quorum() {
+        assert_eq!(agreed_minimum(vec![100, 250, 98], 2), Some(100));
+        assert_eq!(agreed_minimum(vec![100, 250, 98], 3), Some(98));
+        assert_eq!(agreed_minimum(vec![100], 2), None);
+    }
+}
@@ -0,0 +1,39 @@
+use parking_lot::Mutex;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+/// Spaces out the requests sent to an endpoint so that at most
+/// `requests_per_second` go out. An unlimited limiter never waits.
+#[derive(Clone)]
+pub struct RateLimiter {
+    interval: Option<Duration>,
+    next_slot: Arc<Mutex<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: Option<u32>) -> Self {
+        Self {
+            interval: requests_per_second
+                .filter(|requests| *requests > 0)
+                .map(|requests| Duration::from_secs(1) / requests),
+            next_slot: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Waits until the next request may be sent.
+    pub async fn acquire(&self) {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return,
+        };
+
+        let slot = {
+            let mut next_slot = self.next_slot.lock();
+            let slot = std::cmp::max(*next_slot, Instant::now());
+            *next_slot = slot + interval;
+            slot
+        };
+
+        tokio::time::sleep_until(slot).await;
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::BTreeMap;
+use web3::types::H256;
+
+/// Keeps the hashes of the most recent blocks that produced events so that
+/// a chain reorganization can be detected and the fork point located.
+pub struct BlockTracker {
+    max_depth: u64,
+    blocks: BTreeMap<u64, H256>,
+}
+
+impl BlockTracker {
+    pub fn new(max_depth: u64) -> Self {
+        Self {
+            max_depth,
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, number: u64, hash: H256) {
+        self.blocks.insert(number, hash);
+
+        let highest = self.latest().map(|(n, _)| n).unwrap_or(number);
+        let lowest_kept = highest.saturating_sub(self.max_depth);
+        self.blocks = self.blocks.split_off(&lowest_kept);
+    }
+
+    pub fn hash_at(&self, number: u64) -> Option<H256> {
+        self.blocks.get(&number).copied()
+    }
+
+    pub fn latest(&self) -> Option<(u64, H256)> {
+        self.blocks.iter().next_back().map(|(n, h)| (*n, *h))
+    }
+
+    pub fn oldest(&self) -> Option<u64> {
+        self.blocks.keys().next().copied()
+    }
+
+    /// Tracked blocks strictly below `number`, newest first.
+    pub fn blocks_below(&self, number: u64) -> Vec<(u64, H256)> {
+        self.blocks
+            .range(..number)
+            .rev()
+            .map(|(n, h)| (*n, *h))
+            .collect()
+    }
+
+    /// Forgets every block at or above `from`.
+    pub fn rollback(&mut self, from: u64) {
+        self.blocks.split_off(&from);
+    }
+}
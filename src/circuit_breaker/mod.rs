@@ -1,9 +1,16 @@
 use chrono::{DateTime, Duration as ChronoDuration, Utc};
-use parking_lot::RwLock;
+use parking_lot::Mutex;
+use std::collections::HashMap;
 use std::sync::Arc;
+use crate::config::ChainConfig;
 use crate::metrics::MetricsCollector;
 use serde::{Deserialize, Deserializer};
 use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast;
+
+/// State changes kept for subscribers that fall behind.
+const STATE_CHANGE_CAPACITY: usize = 16;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct CircuitBreakerConfig {
@@ -19,6 +26,8 @@ pub struct InternalCircuitBreakerConfig {
     pub failure_threshold: u32,
     pub reset_timeout: ChronoDuration,
     pub half_open_timeout: ChronoDuration,
+    pub half_open_max_calls: u32,
+    pub success_threshold: u32,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -28,14 +37,107 @@ pub enum CircuitState {
     HalfOpen(DateTime<Utc>),
 }
 
+impl CircuitState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open(_) => "open",
+            CircuitState::HalfOpen(_) => "half_open",
+        }
+    }
+
+    /// Value of the `indexer_circuit_breaker_state` gauge.
+    pub fn level(&self) -> i64 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::HalfOpen(_) => 1,
+            CircuitState::Open(_) => 2,
+        }
+    }
+}
+
+/// Circuit breakers of the endpoints of a chain, keyed by URL and shared by
+/// the live connection and historical sync.
+pub type CircuitBreakers = Arc<HashMap<String, CircuitBreaker>>;
+
+/// A transition of a breaker, as sent to its subscribers.
+#[derive(Clone, Debug)]
+pub struct StateChange {
+    pub from: CircuitState,
+    pub to: CircuitState,
+}
+
+struct Inner {
+    state: CircuitState,
+    /// Consecutive failures while closed.
+    failures: u32,
+    /// Successful trial calls while half-open.
+    successes: u32,
+    /// Trial calls handed out while half-open and not reported yet.
+    trials: u32,
+    /// Bumped whenever the trial permits are reset, so that permits handed
+    /// out before do not count against the current trials.
+    generation: u64,
+}
+
+/// Closed → Open after `failure_threshold` consecutive failures; Open →
+/// HalfOpen once `reset_timeout` has elapsed, whether or not anything calls
+/// the breaker; HalfOpen → Closed after `success_threshold` successful trial
+/// calls, or back to Open on the first failed one. At most
+/// `half_open_max_calls` trial calls are in flight at once; permits not
+/// reported within `half_open_timeout` are handed out again. Only calls made
+/// with a `Permit` count as trials.
+#[derive(Clone)]
 pub struct CircuitBreaker {
-    state: Arc<RwLock<CircuitState>>,
+    inner: Arc<Mutex<Inner>>,
     config: InternalCircuitBreakerConfig,
-    failures: Arc<RwLock<u32>>,
     metrics: MetricsCollector,
+    changes: broadcast::Sender<StateChange>,
 }
 
 
+/// A call let through by `try_acquire`. While half-open it holds one of the
+/// limited trial permits, given back if the permit is dropped without an
+/// outcome being reported.
+#[must_use]
+pub struct Permit {
+    breaker: CircuitBreaker,
+    /// Generation of the trial permit taken while half-open.
+    trial: Option<u64>,
+}
+
+impl Permit {
+    pub fn record_success(mut self) {
+        let trial = self.trial.take();
+        self.breaker.on_success(trial);
+    }
+
+    /// Records a failed call, returning whether the breaker is open afterwards.
+    pub fn record_failure(mut self) -> bool {
+        let trial = self.trial.take();
+        self.breaker.on_failure(trial)
+    }
+
+    /// Reports the outcome of an RPC call: only failures of the endpoint
+    /// itself count against it, JSON-RPC errors are answers from a working node.
+    pub fn record<R>(self, result: &crate::error::Result<R>) {
+        match result {
+            Err(e) if e.is_endpoint_failure() => {
+                self.record_failure();
+            }
+            _ => self.record_success(),
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(generation) = self.trial.take() {
+            self.breaker.release(generation);
+        }
+    }
+}
+
 fn deserialize_duration_from_secs<'de, D>(deserializer: D) -> Result<Duration, D::Error>
 where
     D: Deserializer<'de>,
@@ -54,11 +156,19 @@ pub struct HealthCheckConfig {
 
 impl CircuitBreaker {
     pub fn new(config: InternalCircuitBreakerConfig, metrics: MetricsCollector) -> Self {
+        metrics.set_circuit_breaker_state(CircuitState::Closed.level());
+        let (changes, _) = broadcast::channel(STATE_CHANGE_CAPACITY);
         Self {
-            state: Arc::new(RwLock::new(CircuitState::Closed)),
+            inner: Arc::new(Mutex::new(Inner {
+                state: CircuitState::Closed,
+                failures: 0,
+                successes: 0,
+                trials: 0,
+                generation: 0,
+            })),
             config,
-            failures: Arc::new(RwLock::new(0)),
             metrics,
+            changes,
         }
     }
 
@@ -67,62 +177,169 @@ impl CircuitBreaker {
         Self::new(internal_config, metrics)
     }
 
-    pub fn record_success(&self) {
-        let mut state = self.state.write();
-        match *state {
-            CircuitState::HalfOpen(_) => {
-                *state = CircuitState::Closed;
-                *self.failures.write() = 0;
-            }
-            CircuitState::Closed => {
-                *self.failures.write() = 0;
-            }
-            _ => {}
+    /// One breaker per endpoint of `config`, each with its own settings.
+    pub fn for_chain(config: &ChainConfig, metrics: &MetricsCollector) -> CircuitBreakers {
+        let breakers = config.rpcs
+            .iter()
+            .map(|endpoint| {
+                let breaker = Self::new_from_config(
+                    endpoint.circuit_breaker.clone(),
+                    metrics.for_endpoint(&endpoint.url),
+                );
+                (endpoint.url.clone(), breaker)
+            })
+            .collect();
+
+        Arc::new(breakers)
+    }
+
+    /// Receives every state change of this breaker from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<StateChange> {
+        self.changes.subscribe()
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let mut inner = self.inner.lock();
+        self.refresh(&mut inner);
+        inner.state.clone()
+    }
+
+    /// Whether a call would currently be let through, without taking a
+    /// half-open trial permit.
+    pub fn can_execute(&self) -> bool {
+        let mut inner = self.inner.lock();
+        self.refresh(&mut inner);
+        match inner.state {
+            CircuitState::Closed => true,
+            CircuitState::HalfOpen(_) => inner.trials < self.config.half_open_max_calls,
+            CircuitState::Open(_) => false,
         }
     }
 
+    /// Lets a call through, taking one of the limited trial permits when
+    /// half-open. The outcome is reported through the returned permit.
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let mut inner = self.inner.lock();
+        self.refresh(&mut inner);
+        let trial = match inner.state {
+            CircuitState::Closed => None,
+            CircuitState::HalfOpen(_) if inner.trials < self.config.half_open_max_calls => {
+                inner.trials += 1;
+                Some(inner.generation)
+            }
+            _ => return None,
+        };
+
+        Some(Permit {
+            breaker: self.clone(),
+            trial,
+        })
+    }
+
+    /// Records a successful call made without a permit, such as a message
+    /// received on a subscription. It does not count as a half-open trial.
+    pub fn record_success(&self) {
+        self.on_success(None);
+    }
+
+    /// Records a failed call made without a permit, returning whether the
+    /// breaker is open afterwards. It does not count as a half-open trial.
     pub fn record_failure(&self) -> bool {
-        let mut state = self.state.write();
-        let mut failures = self.failures.write();
+        self.on_failure(None)
+    }
+
+    fn on_success(&self, trial: Option<u64>) {
+        let mut inner = self.inner.lock();
+        self.refresh(&mut inner);
+        match inner.state {
+            CircuitState::Closed => inner.failures = 0,
+            CircuitState::HalfOpen(_) if trial == Some(inner.generation) => {
+                inner.trials = inner.trials.saturating_sub(1);
+                inner.successes += 1;
+                if inner.successes >= self.config.success_threshold {
+                    self.transition(&mut inner, CircuitState::Closed);
+                }
+            }
+            // Calls that are not current trials say nothing about recovery,
+            // and late answers to calls made before the breaker opened are dropped.
+            CircuitState::HalfOpen(_) | CircuitState::Open(_) => {}
+        }
+    }
 
-        match *state {
+    fn on_failure(&self, trial: Option<u64>) -> bool {
+        let mut inner = self.inner.lock();
+        self.refresh(&mut inner);
+        match inner.state {
             CircuitState::Closed => {
-                *failures += 1;
-                if *failures >= self.config.failure_threshold {
-                    *state = CircuitState::Open(Utc::now());
-                    self.metrics.record_circuit_breaker_trip();
+                inner.failures += 1;
+                if inner.failures >= self.config.failure_threshold {
+                    self.open(&mut inner);
                     true
                 } else {
                     false
                 }
             }
-            CircuitState::HalfOpen(_) => {
-                *state = CircuitState::Open(Utc::now());
-                self.metrics.record_circuit_breaker_trip();
+            CircuitState::HalfOpen(_) if trial == Some(inner.generation) => {
+                self.open(&mut inner);
                 true
             }
-            CircuitState::Open(opened_at) => {
-                if opened_at + self.config.reset_timeout <= Utc::now() {
-                    *state = CircuitState::HalfOpen(Utc::now());
-                    *failures = 0;
-                    false
-                } else {
-                    true
-                }
-            }
+            CircuitState::HalfOpen(_) => false,
+            CircuitState::Open(_) => true,
         }
     }
 
-    pub fn can_execute(&self) -> bool {
-        let state = self.state.read();
-        match *state {
-            CircuitState::Closed => true,
-            CircuitState::HalfOpen(attempt_at) => {
-                (attempt_at + self.config.half_open_timeout) <= Utc::now()
+    /// Gives back a trial permit whose call ended without an outcome.
+    fn release(&self, generation: u64) {
+        let mut inner = self.inner.lock();
+        if matches!(inner.state, CircuitState::HalfOpen(_)) && inner.generation == generation {
+            inner.trials = inner.trials.saturating_sub(1);
+        }
+    }
+
+    /// Applies the transitions that only depend on time.
+    fn refresh(&self, inner: &mut Inner) {
+        match inner.state {
+            CircuitState::Open(opened_at) if opened_at + self.config.reset_timeout <= Utc::now() => {
+                self.transition(inner, CircuitState::HalfOpen(Utc::now()));
             }
-            CircuitState::Open(opened_at) => {
-                (opened_at + self.config.reset_timeout) <= Utc::now()
+            CircuitState::HalfOpen(since) if inner.trials > 0 && since + self.config.half_open_timeout <= Utc::now() => {
+                tracing::debug!("Half-open trial calls timed out, allowing new ones");
+                inner.trials = 0;
+                inner.generation += 1;
+                inner.state = CircuitState::HalfOpen(Utc::now());
             }
+            _ => {}
+        }
+    }
+
+    fn open(&self, inner: &mut Inner) {
+        self.transition(inner, CircuitState::Open(Utc::now()));
+        self.metrics.record_circuit_breaker_trip();
+
+        // Moves to half-open on time even if nothing calls the breaker, so
+        // that subscribers learn when trial calls are allowed again.
+        if let (Ok(runtime), Ok(reset_timeout)) = (Handle::try_current(), self.config.reset_timeout.to_std()) {
+            let breaker = self.clone();
+            runtime.spawn(async move {
+                tokio::time::sleep(reset_timeout).await;
+                breaker.state();
+            });
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, to: CircuitState) {
+        let from = std::mem::replace(&mut inner.state, to.clone());
+        inner.failures = 0;
+        inner.successes = 0;
+        inner.trials = 0;
+        inner.generation += 1;
+
+        self.metrics.set_circuit_breaker_state(to.level());
+        if std::mem::discriminant(&from) != std::mem::discriminant(&to) {
+            self.metrics.record_circuit_breaker_transition(from.name(), to.name());
+            tracing::info!("Circuit breaker moved from {} to {}", from.name(), to.name());
+            // Nobody listening is not an error.
+            let _ = self.changes.send(StateChange { from, to });
         }
     }
 }
@@ -133,6 +350,126 @@ impl From<crate::config::CircuitBreakerConfig> for InternalCircuitBreakerConfig
             failure_threshold: config.failure_threshold,
             reset_timeout: ChronoDuration::seconds(Duration::from_secs(config.reset_timeout).as_secs() as i64),
             half_open_timeout: ChronoDuration::seconds(Duration::from_secs(config.half_open_timeout).as_secs() as i64),
+            half_open_max_calls: config.half_open_max_calls.max(1),
+            success_threshold: config.success_threshold.max(1),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG: i64 = 3600;
+
+    fn breaker(reset_timeout: i64, half_open_timeout: i64) -> CircuitBreaker {
+        let config = InternalCircuitBreakerConfig {
+            failure_threshold: 2,
+            reset_timeout: ChronoDuration::seconds(reset_timeout),
+            half_open_timeout: ChronoDuration::seconds(half_open_timeout),
+            half_open_max_calls: 2,
+            success_threshold: 2,
+        };
+        CircuitBreaker::new(config, MetricsCollector::new("test", "http://rpc"))
+    }
+
+    /// Opens `breaker` and moves it to half-open, which needs a zero `reset_timeout`.
+    fn half_open(breaker: &CircuitBreaker) {
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(matches!(breaker.state(), CircuitState::HalfOpen(_)));
+    }
+
+    #[test]
+    fn opens_after_failure_threshold_until_reset_timeout() {
+        let breaker = breaker(LONG, LONG);
+
+        assert!(!breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.record_failure());
+        assert!(matches!(breaker.state(), CircuitState::Open(_)));
+        assert!(breaker.try_acquire().is_none());
+    }
+
+    #[test]
+    fn half_opens_once_reset_timeout_elapsed() {
+        let breaker = breaker(0, LONG);
+
+        assert!(!breaker.record_failure());
+        assert!(breaker.record_failure());
+        assert!(matches!(breaker.inner.lock().state, CircuitState::Open(_)));
+        assert!(matches!(breaker.state(), CircuitState::HalfOpen(_)));
+    }
+
+    #[test]
+    fn half_open_limits_trial_permits() {
+        let breaker = breaker(0, LONG);
+        half_open(&breaker);
+
+        let first = breaker.try_acquire().expect("first trial");
+        let second = breaker.try_acquire().expect("second trial");
+        assert!(breaker.try_acquire().is_none());
+        assert!(!breaker.can_execute());
+
+        drop(first);
+        let third = breaker.try_acquire().expect("trial given back by a dropped permit");
+        assert!(breaker.try_acquire().is_none());
+        drop((second, third));
+    }
+
+    #[test]
+    fn closes_after_success_threshold_trials() {
+        let breaker = breaker(0, LONG);
+        half_open(&breaker);
+
+        // Calls made without a permit are not trials.
+        breaker.record_success();
+        breaker.record_success();
+        assert!(matches!(breaker.state(), CircuitState::HalfOpen(_)));
+
+        breaker.try_acquire().expect("trial").record_success();
+        assert!(matches!(breaker.state(), CircuitState::HalfOpen(_)));
+        breaker.try_acquire().expect("trial").record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn failed_trial_reopens() {
+        let breaker = breaker(0, LONG);
+        half_open(&breaker);
+
+        assert!(breaker.try_acquire().expect("trial").record_failure());
+        assert!(matches!(breaker.inner.lock().state, CircuitState::Open(_)));
+    }
+
+    #[test]
+    fn ignores_permits_of_reset_trials() {
+        let breaker = breaker(0, 0);
+        half_open(&breaker);
+
+        let stale = breaker.try_acquire().expect("trial");
+        // The zero `half_open_timeout` resets the outstanding trial.
+        assert!(matches!(breaker.state(), CircuitState::HalfOpen(_)));
+        assert_eq!(breaker.inner.lock().trials, 0);
+
+        assert!(!stale.record_failure());
+        assert!(matches!(breaker.state(), CircuitState::HalfOpen(_)));
+    }
+
+    #[test]
+    fn broadcasts_state_changes() {
+        let breaker = breaker(0, LONG);
+        let mut changes = breaker.subscribe();
+        half_open(&breaker);
+        breaker.try_acquire().expect("trial").record_success();
+        breaker.try_acquire().expect("trial").record_success();
+
+        let transitions: Vec<_> = std::iter::from_fn(|| changes.try_recv().ok())
+            .map(|change| (change.from.name(), change.to.name()))
+            .collect();
+        assert_eq!(
+            transitions,
+            vec![("closed", "open"), ("open", "half_open"), ("half_open", "closed")]
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use std::{env, path::Path};
+use std::{collections::HashMap, env, path::Path};
 
 use serde::Deserialize;
 
@@ -12,6 +12,14 @@ pub enum RpcType {
     Http,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub enum FinalityTag {
+    #[serde(rename = "safe")]
+    Safe,
+    #[serde(rename = "finalized")]
+    Finalized,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct RpcEndpoint {
     pub url: String,
@@ -19,6 +27,8 @@ pub struct RpcEndpoint {
     pub priority: u8,
     pub health_check: HealthCheckConfig,
     pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub max_requests_per_second: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -34,17 +44,179 @@ pub struct CircuitBreakerConfig {
     pub failure_threshold: u32,
     pub reset_timeout: u64,
     pub half_open_timeout: u64,
+    #[serde(default = "default_half_open_max_calls")]
+    pub half_open_max_calls: u32,
+    #[serde(default = "default_success_threshold")]
+    pub success_threshold: u32,
+}
+
+fn default_half_open_max_calls() -> u32 {
+    1
+}
+
+fn default_success_threshold() -> u32 {
+    1
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FactoryConfig {
+    pub event: String,
+    pub parameter: String,
+    pub child_name: String,
+    pub child_abi_path: String,
+}
+
+/// How uint/int event parameters are stored in MongoDB.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum NumericMode {
+    /// Decimal string, lossless but not sortable as a number.
+    #[default]
+    #[serde(rename = "string")]
+    String,
+    /// `Decimal128` when the value has at most 34 digits, a string otherwise.
+    #[serde(rename = "decimal128")]
+    Decimal128,
+    /// `Int64` when the value fits, falling back to `Decimal128` then a string.
+    #[serde(rename = "int64")]
+    Int64,
+    /// Sub-document holding the decimal string and a fixed-width hex form that
+    /// sorts in numeric order.
+    #[serde(rename = "dual")]
+    Dual,
+}
+
+/// Numeric mode per field (`"Event.param"`), per event, or for the whole contract.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct NumericConfig {
+    #[serde(default)]
+    pub default: NumericMode,
+    #[serde(default)]
+    pub events: HashMap<String, NumericMode>,
+    #[serde(default)]
+    pub fields: HashMap<String, NumericMode>,
+}
+
+impl NumericConfig {
+    pub fn mode(&self, event: &str, field: &str) -> NumericMode {
+        self.fields
+            .get(&format!("{}.{}", event, field))
+            .or_else(|| self.events.get(event))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ContractConfig {
+    pub name: String,
+    pub address: String,
+    pub abi_path: String,
+    #[serde(default)]
+    pub start_block: Option<u64>,
+    #[serde(default)]
+    pub factory: Option<FactoryConfig>,
+    #[serde(default)]
+    pub numeric: NumericConfig,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ChainConfig {
     pub name: String,
-    pub contract_address: String,
+    #[serde(default)]
+    pub contract_address: Option<String>,
+    #[serde(default)]
+    pub contracts: Vec<ContractConfig>,
     pub rpcs: Vec<RpcEndpoint>,
     pub starting_block: Option<u64>,
+    #[serde(default = "default_max_reorg_depth")]
+    pub max_reorg_depth: u64,
+    #[serde(default)]
+    pub confirmations: u64,
+    #[serde(default)]
+    pub finality: Option<FinalityTag>,
+    #[serde(default)]
+    pub log_range: LogRangeConfig,
+    #[serde(default = "default_historical_workers")]
+    pub historical_workers: usize,
+    #[serde(default)]
+    pub detect_start_block: bool,
+    #[serde(default)]
+    pub quorum: Option<QuorumConfig>,
 }
 
-#[derive(Debug, Deserialize)]
+fn default_historical_workers() -> usize {
+    1
+}
+
+/// Cross-checked live reads: logs and block hashes are requested from every
+/// endpoint of the chain and only used once `min_agreement` of them return
+/// the same answer.
+#[derive(Debug, Deserialize, Clone)]
+pub struct QuorumConfig {
+    #[serde(default = "default_min_agreement")]
+    pub min_agreement: usize,
+}
+
+fn default_min_agreement() -> usize {
+    2
+}
+
+/// Bounds of the block ranges requested with `eth_getLogs` during historical
+/// sync. Ranges rejected by the provider are halved down to `min_blocks`;
+/// ranges returning fewer than `sparse_logs` logs are doubled up to `max_blocks`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LogRangeConfig {
+    pub initial_blocks: u64,
+    pub min_blocks: u64,
+    pub max_blocks: u64,
+    pub sparse_logs: usize,
+}
+
+impl Default for LogRangeConfig {
+    fn default() -> Self {
+        Self {
+            initial_blocks: 1000,
+            min_blocks: 1,
+            max_blocks: 10000,
+            sparse_logs: 1000,
+        }
+    }
+}
+
+fn default_max_reorg_depth() -> u64 {
+    64
+}
+
+impl ChainConfig {
+    /// Contracts indexed on this chain. A legacy top-level `contract_address`
+    /// is indexed with the ABI at `EVM_INDEXER_ABI_PATH` from `starting_block`.
+    pub fn contracts(&self) -> Result<Vec<ContractConfig>, Error> {
+        let mut contracts = self.contracts.clone();
+
+        if let Some(address) = &self.contract_address {
+            let abi_path = env::var("EVM_INDEXER_ABI_PATH")
+                .map_err(|_| Error::MissingEnvVar("EVM_INDEXER_ABI_PATH".to_string()))?;
+
+            contracts.push(ContractConfig {
+                name: self.name.clone(),
+                address: address.clone(),
+                abi_path,
+                start_block: self.starting_block,
+                factory: None,
+                numeric: NumericConfig::default(),
+            });
+        }
+
+        if contracts.is_empty() {
+            return Err(Error::InvalidConfig(format!("No contracts configured for chain {}", self.name)));
+        }
+
+        Ok(contracts)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct DatabaseConfig {
     pub db_host: String,
     pub db_port: u16,
@@ -53,6 +225,38 @@ pub struct DatabaseConfig {
     pub username: Option<String>,
     #[serde(default)]
     pub password: Option<String>,
+    #[serde(default = "default_storage_circuit_breaker")]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub write_buffer: WriteBufferConfig,
+}
+
+fn default_storage_circuit_breaker() -> CircuitBreakerConfig {
+    CircuitBreakerConfig {
+        failure_threshold: 3,
+        reset_timeout: 30,
+        half_open_timeout: 10,
+        half_open_max_calls: default_half_open_max_calls(),
+        success_threshold: default_success_threshold(),
+    }
+}
+
+/// Where live events are spooled while MongoDB is unreachable, one file per
+/// chain, and how many events each file may hold.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct WriteBufferConfig {
+    pub path: String,
+    pub max_events: usize,
+}
+
+impl Default for WriteBufferConfig {
+    fn default() -> Self {
+        Self {
+            path: "./write_buffer".to_string(),
+            max_events: 100_000,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -83,7 +287,23 @@ impl Config {
             .add_source(config::File::with_name(&config_path))
             .add_source(config::Environment::with_prefix("EVM_INDEXER"));
 
-        let config = builder.build()?;
-        Ok(config.try_deserialize()?)
+        let config: Self = builder.build()?.try_deserialize()?;
+        for chain in &config.chains {
+            if chain.rpcs.is_empty() {
+                return Err(Error::InvalidConfig(format!("No RPC endpoints configured for chain {}", chain.name)));
+            }
+
+            let range = &chain.log_range;
+            if range.min_blocks == 0 || range.min_blocks > range.max_blocks {
+                return Err(Error::InvalidConfig(format!(
+                    "log_range of chain {} needs 1 <= min_blocks <= max_blocks, got {} and {}",
+                    chain.name,
+                    range.min_blocks,
+                    range.max_blocks
+                )));
+            }
+        }
+
+        Ok(config)
     }
 }
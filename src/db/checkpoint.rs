@@ -0,0 +1,84 @@
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::UpdateOptions;
+use mongodb::{Collection, Database};
+use web3::types::{H160, H256};
+use crate::db::models::Checkpoint;
+use crate::error::Result;
+
+/// Last fully processed block per chain and contract, persisted so that the
+/// indexer can resume where it stopped instead of re-scanning from the
+/// configured starting block.
+#[derive(Clone)]
+pub struct CheckpointStore {
+    collection: Collection<Checkpoint>,
+}
+
+impl CheckpointStore {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection::<Checkpoint>("checkpoints"),
+        }
+    }
+
+    pub async fn load(&self, chain_name: &str, contract_address: &str) -> Result<Option<Checkpoint>> {
+        let checkpoint = self.collection
+            .find_one(
+                doc! {
+                    "chain_name": chain_name,
+                    "contract_address": contract_address.to_lowercase(),
+                },
+                None,
+            )
+            .await?;
+
+        Ok(checkpoint)
+    }
+
+    /// Must only be called once every event up to `block_number` has been
+    /// written: event writes are idempotent, so a crash between the two
+    /// writes only replays the last batch on restart.
+    pub async fn save(
+        &self,
+        chain_name: &str,
+        contract_address: &str,
+        block_number: u64,
+        block_hash: H256,
+    ) -> Result<()> {
+        let contract_address = contract_address.to_lowercase();
+        self.collection
+            .update_one(
+                doc! {
+                    "chain_name": chain_name,
+                    "contract_address": &contract_address,
+                },
+                doc! {
+                    "$set": {
+                        "chain_name": chain_name,
+                        "contract_address": &contract_address,
+                        "block_number": block_number as i64,
+                        "block_hash": format!("{:?}", block_hash),
+                        "updated_at": DateTime::now(),
+                    }
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Saves the same checkpoint for every contract covered by a combined log filter.
+    pub async fn save_all(
+        &self,
+        chain_name: &str,
+        contracts: &[H160],
+        block_number: u64,
+        block_hash: H256,
+    ) -> Result<()> {
+        for contract in contracts {
+            self.save(chain_name, &format!("{:?}", contract), block_number, block_hash).await?;
+        }
+
+        Ok(())
+    }
+}
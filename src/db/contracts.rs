@@ -0,0 +1,63 @@
+use futures::TryStreamExt;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{IndexOptions, ReplaceOptions};
+use mongodb::{Collection, Database, IndexModel};
+use crate::db::models::DiscoveredContract;
+use crate::decoder::registry::RegisteredContract;
+use crate::error::Result;
+
+/// Child contracts discovered through factory events, persisted so that
+/// they keep being indexed after a restart.
+#[derive(Clone)]
+pub struct DiscoveredContractStore {
+    collection: Collection<DiscoveredContract>,
+}
+
+impl DiscoveredContractStore {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection::<DiscoveredContract>("discovered_contracts"),
+        }
+    }
+
+    pub async fn ensure_indexes(&self) -> Result<()> {
+        let identity = IndexModel::builder()
+            .keys(doc! { "chain_name": 1, "address": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+
+        self.collection.create_index(identity, None).await?;
+        Ok(())
+    }
+
+    pub async fn load(&self, chain_name: &str) -> Result<Vec<DiscoveredContract>> {
+        let contracts = self.collection
+            .find(doc! { "chain_name": chain_name }, None)
+            .await?
+            .try_collect()
+            .await?;
+
+        Ok(contracts)
+    }
+
+    pub async fn save(&self, chain_name: &str, contract: &RegisteredContract) -> Result<()> {
+        let discovered = DiscoveredContract {
+            chain_name: chain_name.to_string(),
+            factory_address: format!("{:?}", contract.factory.unwrap_or_default()),
+            name: contract.name.clone(),
+            address: format!("{:?}", contract.address),
+            start_block: contract.start_block.unwrap_or_default(),
+            discovered_at: DateTime::now(),
+        };
+
+        self.collection
+            .replace_one(
+                doc! { "chain_name": chain_name, "address": &discovered.address },
+                &discovered,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
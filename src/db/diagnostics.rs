@@ -0,0 +1,33 @@
+use mongodb::bson::doc;
+use mongodb::{Collection, Database, IndexModel};
+use crate::db::models::QuorumDisagreement;
+use crate::error::Result;
+
+/// Disagreements between the endpoints of a chain on cross-checked reads,
+/// kept for investigating misbehaving providers.
+#[derive(Clone)]
+pub struct QuorumDiagnosticsStore {
+    collection: Collection<QuorumDisagreement>,
+}
+
+impl QuorumDiagnosticsStore {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection::<QuorumDisagreement>("quorum_disagreements"),
+        }
+    }
+
+    pub async fn ensure_indexes(&self) -> Result<()> {
+        let recent = IndexModel::builder()
+            .keys(doc! { "chain_name": 1, "recorded_at": -1 })
+            .build();
+
+        self.collection.create_index(recent, None).await?;
+        Ok(())
+    }
+
+    pub async fn record(&self, disagreement: &QuorumDisagreement) -> Result<()> {
+        self.collection.insert_one(disagreement, None).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+use futures::TryStreamExt;
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::error::{BulkWriteFailure, ErrorKind};
+use mongodb::options::{FindOneOptions, FindOptions, IndexOptions, InsertManyOptions, ReplaceOptions};
+use mongodb::{Collection, Database, IndexModel};
+use std::collections::HashMap;
+use web3::types::Log;
+use crate::db::models::EventLog;
+use crate::error::Result;
+
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Access to the `events` collection. An event is identified by its chain,
+/// transaction hash and log index, so every write is idempotent.
+#[derive(Clone)]
+pub struct EventStore {
+    collection: Collection<EventLog>,
+}
+
+impl EventStore {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection::<EventLog>("events"),
+        }
+    }
+
+    /// Creates the indexes of the collection. The unique `event_identity` index
+    /// cannot be built while events stored by a version without log indexes
+    /// remain, see `migrate-events`.
+    pub async fn ensure_indexes(&self) -> Result<()> {
+        let identity = IndexModel::builder()
+            .keys(doc! { "chain_name": 1, "transaction_hash": 1, "log_index": 1 })
+            .options(IndexOptions::builder().unique(true).name("event_identity".to_string()).build())
+            .build();
+
+        let blocks = IndexModel::builder()
+            .keys(doc! { "chain_name": 1, "block_number": 1 })
+            .build();
+
+        self.collection.create_indexes(vec![identity, blocks], None).await?;
+        Ok(())
+    }
+
+    pub async fn upsert(&self, event: &EventLog) -> Result<()> {
+        self.collection
+            .replace_one(
+                Self::identity(&event.chain_name, &event.transaction_hash, event.log_index),
+                event,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Upserts `events` one by one, overwriting the stored version of each.
+    pub async fn upsert_many(&self, events: &[EventLog]) -> Result<()> {
+        for event in events {
+            self.upsert(event).await?;
+        }
+        Ok(())
+    }
+
+    /// Inserts `events` with a single unordered write, returning the number of
+    /// new documents. Events that are already stored are left untouched.
+    pub async fn insert_many(&self, events: &[EventLog]) -> Result<u64> {
+        if events.is_empty() {
+            return Ok(0);
+        }
+
+        let options = InsertManyOptions::builder().ordered(false).build();
+        match self.collection.insert_many(events, options).await {
+            Ok(result) => Ok(result.inserted_ids.len() as u64),
+            Err(e) => match e.kind.as_ref() {
+                ErrorKind::BulkWrite(BulkWriteFailure {
+                    write_errors: Some(write_errors),
+                    write_concern_error: None,
+                    ..
+                }) if write_errors.iter().all(|error| error.code == DUPLICATE_KEY) => {
+                    Ok((events.len() - write_errors.len()) as u64)
+                }
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    /// Deletes the event emitted by `log`, returning the number of removed documents.
+    pub async fn delete_log(&self, chain_name: &str, log: &Log) -> Result<u64> {
+        let filter = Self::identity(
+            chain_name,
+            &format!("{:?}", log.transaction_hash.unwrap_or_default()),
+            log.log_index.unwrap_or_default().as_u64(),
+        );
+
+        Ok(self.collection.delete_many(filter, None).await?.deleted_count)
+    }
+
+    /// Deletes every event of `chain_name` above `block_number`.
+    pub async fn delete_after_block(&self, chain_name: &str, block_number: u64) -> Result<u64> {
+        let filter = doc! {
+            "chain_name": chain_name,
+            "block_number": { "$gt": block_number as i64 },
+        };
+
+        Ok(self.collection.delete_many(filter, None).await?.deleted_count)
+    }
+
+    /// Events of `chain_name` in `[from_block, to_block]` by transaction hash
+    /// and log index, without their `indexed_at` time. Only events indexed at
+    /// or after `since` are returned when it is set.
+    pub async fn contents_in_range(
+        &self,
+        chain_name: &str,
+        from_block: u64,
+        to_block: u64,
+        since: Option<DateTime>,
+    ) -> Result<HashMap<(String, u64), Document>> {
+        let mut filter = Self::range(chain_name, from_block, to_block);
+        if let Some(since) = since {
+            filter.insert("indexed_at", doc! { "$gte": since });
+        }
+        let options = FindOptions::builder()
+            .projection(doc! { "_id": 0, "indexed_at": 0 })
+            .build();
+
+        let mut cursor = self.collection
+            .clone_with_type::<Document>()
+            .find(filter, options)
+            .await?;
+
+        let mut events = HashMap::new();
+        while let Some(event) = cursor.try_next().await? {
+            if let (Ok(transaction_hash), Ok(log_index)) = (event.get_str("transaction_hash"), event.get_i64("log_index")) {
+                events.insert((transaction_hash.to_string(), log_index as u64), event);
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Deletes the events of `chain_name` in `[from_block, to_block]` that were
+    /// not indexed again since `since`.
+    pub async fn delete_indexed_before(&self, chain_name: &str, from_block: u64, to_block: u64, since: DateTime) -> Result<u64> {
+        let mut filter = Self::range(chain_name, from_block, to_block);
+        filter.insert("indexed_at", doc! { "$not": { "$gte": since } });
+        Ok(self.collection.delete_many(filter, None).await?.deleted_count)
+    }
+
+    /// Lowest and highest block holding events of `chain_name` stored without
+    /// a log index by a previous version.
+    pub async fn legacy_block_range(&self, chain_name: &str) -> Result<Option<(u64, u64)>> {
+        let filter = Self::legacy(chain_name);
+        let events = self.collection.clone_with_type::<Document>();
+
+        let first = events
+            .find_one(filter.clone(), FindOneOptions::builder().sort(doc! { "block_number": 1 }).build())
+            .await?;
+        let last = events
+            .find_one(filter, FindOneOptions::builder().sort(doc! { "block_number": -1 }).build())
+            .await?;
+
+        Ok(first.zip(last).and_then(|(first, last)| {
+            let from_block = first.get_i64("block_number").ok()?;
+            let to_block = last.get_i64("block_number").ok()?;
+            Some((from_block as u64, to_block as u64))
+        }))
+    }
+
+    /// Deletes the events of `chain_name` stored without a log index.
+    pub async fn delete_legacy(&self, chain_name: &str) -> Result<u64> {
+        Ok(self.collection.delete_many(Self::legacy(chain_name), None).await?.deleted_count)
+    }
+
+    fn legacy(chain_name: &str) -> Document {
+        doc! {
+            "chain_name": chain_name,
+            "log_index": { "$exists": false },
+        }
+    }
+
+    fn range(chain_name: &str, from_block: u64, to_block: u64) -> Document {
+        doc! {
+            "chain_name": chain_name,
+            "block_number": { "$gte": from_block as i64, "$lte": to_block as i64 },
+        }
+    }
+
+    fn identity(chain_name: &str, transaction_hash: &str, log_index: u64) -> Document {
+        doc! {
+            "chain_name": chain_name,
+            "transaction_hash": transaction_hash,
+            "log_index": log_index as i64,
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use futures::TryStreamExt;
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::options::{IndexOptions, UpdateOptions};
+use mongodb::{Collection, Database, IndexModel};
+use web3::types::Log;
+use crate::db::models::FailedLog;
+use crate::error::{Error, Result};
+
+/// Dead-letter collection for logs that could not be decoded or stored.
+/// Failing the same log again increments its attempt count.
+#[derive(Clone)]
+pub struct FailedLogStore {
+    collection: Collection<FailedLog>,
+}
+
+impl FailedLogStore {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection::<FailedLog>("failed_logs"),
+        }
+    }
+
+    pub async fn ensure_indexes(&self) -> Result<()> {
+        let identity = IndexModel::builder()
+            .keys(doc! { "chain_name": 1, "transaction_hash": 1, "log_index": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+
+        self.collection.create_index(identity, None).await?;
+        Ok(())
+    }
+
+    pub async fn record(&self, chain_name: &str, log: &Log, error: &Error) -> Result<()> {
+        let now = DateTime::now();
+        let transaction_hash = format!("{:?}", log.transaction_hash.unwrap_or_default());
+        let log_index = log.log_index.unwrap_or_default().as_u64();
+        let topics: Vec<String> = log.topics.iter().map(|topic| format!("{:?}", topic)).collect();
+
+        self.collection
+            .update_one(
+                Self::identity(chain_name, &transaction_hash, log_index),
+                doc! {
+                    "$set": {
+                        "contract_address": format!("{:?}", log.address),
+                        "block_number": log.block_number.unwrap_or_default().as_u64() as i64,
+                        "block_hash": format!("{:?}", log.block_hash.unwrap_or_default()),
+                        "transaction_index": log.transaction_index.unwrap_or_default().as_u64() as i64,
+                        "topics": topics,
+                        "data": format!("0x{}", hex::encode(&log.data.0)),
+                        "reason": error.to_string(),
+                        "last_failed_at": now,
+                    },
+                    "$inc": { "attempts": 1 },
+                    "$setOnInsert": { "first_failed_at": now },
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Counts another failed attempt of an already recorded log.
+    pub async fn record_attempt(&self, failed: &FailedLog, error: &Error) -> Result<()> {
+        self.collection
+            .update_one(
+                Self::identity(&failed.chain_name, &failed.transaction_hash, failed.log_index),
+                doc! {
+                    "$set": {
+                        "reason": error.to_string(),
+                        "last_failed_at": DateTime::now(),
+                    },
+                    "$inc": { "attempts": 1 },
+                },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn load(&self, chain_name: &str) -> Result<Vec<FailedLog>> {
+        let failed = self.collection
+            .find(doc! { "chain_name": chain_name }, None)
+            .await?
+            .try_collect()
+            .await?;
+
+        Ok(failed)
+    }
+
+    pub async fn remove(&self, failed: &FailedLog) -> Result<()> {
+        self.collection
+            .delete_one(
+                Self::identity(&failed.chain_name, &failed.transaction_hash, failed.log_index),
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    fn identity(chain_name: &str, transaction_hash: &str, log_index: u64) -> Document {
+        doc! {
+            "chain_name": chain_name,
+            "transaction_hash": transaction_hash,
+            "log_index": log_index as i64,
+        }
+    }
+}
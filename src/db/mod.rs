@@ -1,9 +1,19 @@
 use mongodb::options::ClientOptions;
 use mongodb::{Client, Database};
 use crate::config::DatabaseConfig;
+use crate::db::contracts::DiscoveredContractStore;
+use crate::db::diagnostics::QuorumDiagnosticsStore;
+use crate::db::events::EventStore;
+use crate::db::failed_logs::FailedLogStore;
 use crate::error::Result;
 
+pub mod checkpoint;
+pub mod contracts;
+pub mod diagnostics;
+pub mod events;
+pub mod failed_logs;
 pub mod models;
+pub mod write_buffer;
 
 pub struct DatabaseConnection {
     pub client: Client,
@@ -40,6 +50,16 @@ impl DatabaseConnection {
             .await?;
 
         let database = client.database(&config.db_name);
+        if let Err(e) = EventStore::new(&database).ensure_indexes().await {
+            tracing::warn!(
+                "Failed to create the indexes of the events collection, run `evm-indexer migrate-events` \
+                 to upgrade events stored by a previous version: {:?}",
+                e
+            );
+        }
+        DiscoveredContractStore::new(&database).ensure_indexes().await?;
+        FailedLogStore::new(&database).ensure_indexes().await?;
+        QuorumDiagnosticsStore::new(&database).ensure_indexes().await?;
         
         tracing::info!("Successfully connected to MongoDB at {}:{}", config.db_host, config.db_port);
         
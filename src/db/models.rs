@@ -1,14 +1,136 @@
 use serde::{Serialize, Deserialize};
 use mongodb::bson::{Document, DateTime};
+use std::str::FromStr;
+use crate::error::{Error, Result};
+use web3::types::{Bytes, Index, Log, H160, H256, U256, U64};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EventLog {
     pub chain_name: String,
+    pub contract_address: String,
     pub event_name: String,
     pub block_number: u64,
+    pub block_hash: String,
     pub transaction_hash: String,
+    pub transaction_index: u64,
+    pub log_index: u64,
     pub params: Document,
-    pub timestamp: DateTime,
+    pub block_timestamp: DateTime,
+    pub indexed_at: DateTime,
+}
+
+impl EventLog {
+    pub fn new(
+        chain_name: &str,
+        event_name: &str,
+        params: Document,
+        log: &Log,
+        block_timestamp: DateTime,
+    ) -> Self {
+        Self {
+            chain_name: chain_name.to_string(),
+            contract_address: format!("{:?}", log.address),
+            event_name: event_name.to_string(),
+            block_number: log.block_number.unwrap_or_default().as_u64(),
+            block_hash: format!("{:?}", log.block_hash.unwrap_or_default()),
+            transaction_hash: format!("{:?}", log.transaction_hash.unwrap_or_default()),
+            transaction_index: log.transaction_index.unwrap_or_default().as_u64(),
+            log_index: log.log_index.unwrap_or_default().as_u64(),
+            params,
+            block_timestamp,
+            indexed_at: DateTime::now(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Checkpoint {
+    pub chain_name: String,
+    pub contract_address: String,
+    pub block_number: u64,
+    pub block_hash: String,
+    pub updated_at: DateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DiscoveredContract {
+    pub chain_name: String,
+    pub factory_address: String,
+    pub name: String,
+    pub address: String,
+    pub start_block: u64,
+    pub discovered_at: DateTime,
+}
+
+/// Answers of the endpoints of a chain to a cross-checked read that were not
+/// unanimous.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QuorumDisagreement {
+    pub chain_name: String,
+    pub query: String,
+    pub quorum_reached: bool,
+    pub answers: Vec<QuorumAnswer>,
+    pub recorded_at: DateTime,
+}
+
+/// One distinct answer and the endpoints that returned it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QuorumAnswer {
+    pub endpoints: Vec<String>,
+    pub answer: String,
+}
+
+/// A log that could not be decoded or stored, kept with everything needed to
+/// process it again once the cause (usually the ABI) is fixed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FailedLog {
+    pub chain_name: String,
+    pub contract_address: String,
+    pub block_number: u64,
+    pub block_hash: String,
+    pub transaction_hash: String,
+    pub transaction_index: u64,
+    pub log_index: u64,
+    pub topics: Vec<String>,
+    pub data: String,
+    pub reason: String,
+    pub attempts: u32,
+    pub first_failed_at: DateTime,
+    pub last_failed_at: DateTime,
+}
+
+impl FailedLog {
+    /// Rebuilds the original RPC log, failing on any stored field that does
+    /// not parse rather than guessing it.
+    pub fn to_log(&self) -> Result<Log> {
+        let topics = self.topics
+            .iter()
+            .map(|topic| Self::parse_hash("topic", topic))
+            .collect::<Result<Vec<H256>>>()?;
+        let data = hex::decode(self.data.trim_start_matches("0x"))
+            .map_err(|e| Error::LogError(format!("Invalid data {:?} in failed log: {}", self.data, e)))?;
+
+        Ok(Log {
+            address: H160::from_str(&self.contract_address).map_err(|e| {
+                Error::LogError(format!("Invalid contract address {:?} in failed log: {}", self.contract_address, e))
+            })?,
+            topics,
+            data: Bytes(data),
+            block_hash: Some(Self::parse_hash("block hash", &self.block_hash)?),
+            block_number: Some(U64::from(self.block_number)),
+            transaction_hash: Some(Self::parse_hash("transaction hash", &self.transaction_hash)?),
+            transaction_index: Some(Index::from(self.transaction_index)),
+            log_index: Some(U256::from(self.log_index)),
+            transaction_log_index: None,
+            log_type: None,
+            removed: None,
+        })
+    }
+
+    fn parse_hash(field: &str, value: &str) -> Result<H256> {
+        H256::from_str(value)
+            .map_err(|e| Error::LogError(format!("Invalid {} {:?} in failed log: {}", field, value, e)))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
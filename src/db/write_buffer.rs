@@ -0,0 +1,179 @@
+use mongodb::bson::{self, Bson};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use crate::db::events::EventStore;
+use crate::db::models::EventLog;
+use crate::error::{Error, Result};
+
+/// Bounded on-disk spool for events received while MongoDB is unreachable.
+/// Events are appended as canonical extended JSON, one per line, and written
+/// back in the order they were received once the database accepts writes.
+pub struct WriteBuffer {
+    path: PathBuf,
+    max_events: usize,
+    len: Mutex<usize>,
+}
+
+impl WriteBuffer {
+    /// Opens the buffer of `chain_name` under `dir`, picking up events left
+    /// over by a previous run.
+    pub async fn open(dir: &str, chain_name: &str, max_events: usize) -> Result<Self> {
+        fs::create_dir_all(dir).await?;
+
+        let file_name: String = chain_name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        let path = Path::new(dir).join(format!("{}.jsonl", file_name));
+
+        let buffer = Self {
+            path,
+            max_events,
+            len: Mutex::new(0),
+        };
+        let len = buffer.read().await?.len();
+        if len > 0 {
+            tracing::warn!("Write buffer {} holds {} events from a previous run", buffer.path.display(), len);
+        }
+        *buffer.len.lock().await = len;
+
+        Ok(buffer)
+    }
+
+    pub async fn len(&self) -> usize {
+        *self.len.lock().await
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    pub async fn is_full(&self) -> bool {
+        self.len().await >= self.max_events
+    }
+
+    pub async fn push(&self, event: &EventLog) -> Result<()> {
+        let mut len = self.len.lock().await;
+        if *len >= self.max_events {
+            return Err(Error::WriteBufferFull(self.path.display().to_string()));
+        }
+
+        let mut line = Self::encode(event)?;
+        line.push('\n');
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.sync_data().await?;
+
+        *len += 1;
+        Ok(())
+    }
+
+    /// Upserts the buffered events in order, keeping those after the first
+    /// failed write for the next attempt. Returns the number of events written.
+    pub async fn flush(&self, events: &EventStore) -> Result<usize> {
+        let mut len = self.len.lock().await;
+        let buffered = self.read().await?;
+
+        for (index, event) in buffered.iter().enumerate() {
+            if let Err(e) = events.upsert(event).await {
+                self.write(&buffered[index..]).await?;
+                *len = buffered.len() - index;
+                return Err(e);
+            }
+        }
+
+        self.write(&[]).await?;
+        *len = 0;
+        Ok(buffered.len())
+    }
+
+    /// Drops the buffered events for which `keep` returns false, returning how
+    /// many were dropped.
+    pub async fn retain(&self, keep: impl Fn(&EventLog) -> bool) -> Result<usize> {
+        let mut len = self.len.lock().await;
+        if *len == 0 {
+            return Ok(0);
+        }
+
+        let buffered = self.read().await?;
+        let kept: Vec<EventLog> = buffered.iter().filter(|event| keep(event)).cloned().collect();
+        let dropped = buffered.len() - kept.len();
+        if dropped > 0 {
+            self.write(&kept).await?;
+            *len = kept.len();
+        }
+
+        Ok(dropped)
+    }
+
+    /// Reads the buffered events. A last line that cannot be decoded was cut
+    /// short by a crash during `push` and is truncated away; corruption
+    /// anywhere else is an error.
+    async fn read(&self) -> Result<Vec<EventLog>> {
+        let content = match fs::read_to_string(&self.path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let lines: Vec<&str> = content.lines().filter(|line| !line.trim().is_empty()).collect();
+        let mut events = Vec::with_capacity(lines.len());
+        for (index, line) in lines.iter().enumerate() {
+            match Self::decode(line) {
+                Ok(event) => events.push(event),
+                Err(e) if index + 1 == lines.len() => {
+                    tracing::warn!(
+                        "Dropping {} bytes of an incomplete last line of write buffer {}: {:?}",
+                        line.len(),
+                        self.path.display(),
+                        e
+                    );
+                    self.write(&events).await?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Replaces the content of the buffer with `events` through a temporary
+    /// file, so that a crash never leaves a half-written buffer behind.
+    async fn write(&self, events: &[EventLog]) -> Result<()> {
+        let mut content = String::new();
+        for event in events {
+            content.push_str(&Self::encode(event)?);
+            content.push('\n');
+        }
+
+        let tmp = self.path.with_extension("jsonl.tmp");
+        let mut file = fs::File::create(&tmp).await?;
+        file.write_all(content.as_bytes()).await?;
+        file.sync_data().await?;
+        fs::rename(&tmp, &self.path).await?;
+
+        Ok(())
+    }
+
+    fn encode(event: &EventLog) -> Result<String> {
+        let document = bson::to_document(event)
+            .map_err(|e| Error::StorageError(format!("Failed to encode buffered event: {}", e)))?;
+        Ok(Bson::Document(document).into_canonical_extjson().to_string())
+    }
+
+    fn decode(line: &str) -> Result<EventLog> {
+        let value: serde_json::Value = serde_json::from_str(line)
+            .map_err(|e| Error::StorageError(format!("Corrupted write buffer line: {}", e)))?;
+        let bson = Bson::try_from(value)
+            .map_err(|e| Error::StorageError(format!("Corrupted write buffer line: {}", e)))?;
+        bson::from_bson(bson)
+            .map_err(|e| Error::StorageError(format!("Failed to decode buffered event: {}", e)))
+    }
+}
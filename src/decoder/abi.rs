@@ -1,41 +1,166 @@
-use ethabi::{Contract, RawLog, Token};
-use mongodb::bson::Document;
+use ethabi::{Contract, Event, Log, RawLog, Token};
+use mongodb::bson::{Bson, Document};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::sync::Arc;
-use crate::error::Result;
+use web3::types::H256;
+use crate::config::{NumericConfig, NumericMode};
+use crate::decoder::numeric::{int_to_bson, uint_to_bson};
+use crate::error::{Error, Result};
 
+/// Name and nested components of an ABI parameter. ethabi drops the names of
+/// tuple components, so they are read from the JSON ABI separately.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ParamLayout {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub components: Vec<ParamLayout>,
+}
+
+#[derive(Deserialize)]
+struct AbiEntry {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    inputs: Vec<ParamLayout>,
+}
+
+#[derive(Clone)]
+struct KnownEvent {
+    event: Event,
+    layout: Vec<ParamLayout>,
+}
+
+impl KnownEvent {
+    fn indexed_count(&self) -> usize {
+        self.event.inputs.iter().filter(|input| input.indexed).count()
+    }
+}
+
+/// Decodes logs by looking their topic0 up in a table built from the ABI.
+/// Overloads sharing a signature are told apart by their number of indexed
+/// parameters; anonymous events, which have no topic0, are tried last.
 #[derive(Clone)]
 pub struct EventDecoder {
-    contract: Arc<Contract>,
+    by_topic: Arc<HashMap<H256, Vec<KnownEvent>>>,
+    anonymous: Arc<Vec<KnownEvent>>,
+    numeric: Arc<NumericConfig>,
 }
 
 impl EventDecoder {
     pub fn new(contract: Arc<Contract>) -> Self {
-        Self { contract }
+        Self::from_events(contract.events().map(|event| (event.clone(), Vec::new())))
+    }
+
+    /// Builds a decoder from a JSON ABI, keeping the component names of tuple
+    /// parameters so that structs are stored as named sub-documents.
+    pub fn from_json(abi: &str) -> Result<Self> {
+        let contract = Contract::load(abi.as_bytes())?;
+        let entries: Vec<AbiEntry> = serde_json::from_str(abi).map_err(ethabi::Error::from)?;
+
+        let mut events = Vec::new();
+        let mut overloads: HashMap<&str, usize> = HashMap::new();
+        for entry in entries.iter().filter(|entry| entry.kind == "event") {
+            let index = overloads.entry(entry.name.as_str()).or_default();
+            if let Some(event) = contract.events_by_name(&entry.name).ok().and_then(|events| events.get(*index)) {
+                events.push((event.clone(), entry.inputs.clone()));
+            }
+            *index += 1;
+        }
+
+        Ok(Self::from_events(events.into_iter()))
+    }
+
+    fn from_events(events: impl Iterator<Item = (Event, Vec<ParamLayout>)>) -> Self {
+        let mut by_topic: HashMap<H256, Vec<KnownEvent>> = HashMap::new();
+        let mut anonymous = Vec::new();
+
+        for (event, layout) in events {
+            let known = KnownEvent { event, layout };
+            if known.event.anonymous {
+                anonymous.push(known);
+            } else {
+                by_topic.entry(known.event.signature()).or_default().push(known);
+            }
+        }
+
+        Self {
+            by_topic: Arc::new(by_topic),
+            anonymous: Arc::new(anonymous),
+            numeric: Arc::new(NumericConfig::default()),
+        }
+    }
+
+    pub fn with_numeric(mut self, numeric: NumericConfig) -> Self {
+        self.numeric = Arc::new(numeric);
+        self
     }
 
     pub fn decode_log(&self, raw_log: RawLog) -> Result<(String, Document)> {
-        for event in self.contract.events() {
-            if let Ok(decoded) = event.parse_log(raw_log.clone()) {
-                let mut params = Document::new();
-                for param in decoded.params {
-                    let value = self.token_to_bson(param.value);
-                    params.insert(param.name, value);
+        if let Some(candidates) = raw_log.topics.first().and_then(|topic| self.by_topic.get(topic)) {
+            let indexed = raw_log.topics.len() - 1;
+            let mut reason = format!("no overload takes {} indexed parameters", indexed);
+
+            for known in candidates.iter().filter(|known| known.indexed_count() == indexed) {
+                match known.event.parse_log(raw_log.clone()) {
+                    Ok(decoded) => return Ok(self.to_document(known, decoded)),
+                    Err(e) => reason = e.to_string(),
                 }
-                return Ok((event.name.clone(), params));
+            }
+
+            return Err(Error::EventDecodeError(candidates[0].event.name.clone(), reason));
+        }
+
+        let indexed = raw_log.topics.len();
+        for known in self.anonymous.iter().filter(|known| known.indexed_count() == indexed) {
+            if let Ok(decoded) = known.event.parse_log(raw_log.clone()) {
+                return Ok(self.to_document(known, decoded));
             }
         }
-        Err(crate::error::Error::UnknownEvent)
+
+        Err(Error::UnknownEvent)
     }
 
-    fn token_to_bson(&self, token: Token) -> mongodb::bson::Bson {
+    fn to_document(&self, known: &KnownEvent, decoded: Log) -> (String, Document) {
+        let mut params = Document::new();
+        for (index, param) in decoded.params.into_iter().enumerate() {
+            let layout = known.layout.get(index);
+            let mode = self.numeric.mode(&known.event.name, &param.name);
+            let value = Self::token_to_bson(param.value, layout, mode);
+            params.insert(param.name, value);
+        }
+        (known.event.name.clone(), params)
+    }
+
+    fn token_to_bson(token: Token, layout: Option<&ParamLayout>, mode: NumericMode) -> Bson {
         match token {
-            Token::Address(addr) => mongodb::bson::Bson::String(format!("{:?}", addr)),
-            Token::Uint(num) => mongodb::bson::Bson::String(num.to_string()),
-            Token::Int(num) => mongodb::bson::Bson::String(num.to_string()),
-            Token::Bool(b) => mongodb::bson::Bson::Boolean(b),
-            Token::String(s) => mongodb::bson::Bson::String(s),
-            Token::Bytes(b) => mongodb::bson::Bson::String(hex::encode(b)),
-            _ => mongodb::bson::Bson::Null,
+            Token::Address(addr) => Bson::String(format!("{:?}", addr)),
+            Token::Uint(num) => uint_to_bson(num, mode),
+            Token::Int(num) => int_to_bson(num, mode),
+            Token::Bool(b) => Bson::Boolean(b),
+            Token::String(s) => Bson::String(s),
+            Token::Bytes(b) | Token::FixedBytes(b) => Bson::String(hex::encode(b)),
+            Token::Array(tokens) | Token::FixedArray(tokens) => Bson::Array(
+                tokens
+                    .into_iter()
+                    .map(|token| Self::token_to_bson(token, layout, mode))
+                    .collect(),
+            ),
+            Token::Tuple(tokens) => {
+                let mut fields = Document::new();
+                for (index, token) in tokens.into_iter().enumerate() {
+                    let component = layout.and_then(|layout| layout.components.get(index));
+                    let name = component
+                        .map(|component| component.name.clone())
+                        .filter(|name| !name.is_empty())
+                        .unwrap_or_else(|| index.to_string());
+                    fields.insert(name, Self::token_to_bson(token, component, mode));
+                }
+                Bson::Document(fields)
+            }
         }
     }
 }
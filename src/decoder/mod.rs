@@ -1,7 +1,10 @@
 pub mod abi;
+pub mod numeric;
+pub mod registry;
 
 use std::{env, path::Path, sync::Arc};
 use ethabi::Contract;
+use crate::decoder::abi::EventDecoder;
 use crate::error::Result;
 
 #[derive(Clone)]
@@ -20,10 +23,23 @@ impl DecoderConfig {
 
         Ok(Self { abi_path })
     }
+
+    pub fn from_path(abi_path: &str) -> Result<Self> {
+        if !Path::new(abi_path).exists() {
+            return Err(crate::error::Error::AbiFileNotFound(abi_path.to_string()));
+        }
+
+        Ok(Self { abi_path: abi_path.to_string() })
+    }
     
     pub async fn load_contract(&self) -> Result<Arc<Contract>> {
         let abi_file = tokio::fs::read_to_string(&self.abi_path).await?;
         let contract = Contract::load(abi_file.as_bytes())?;
         Ok(Arc::new(contract))
     }
+
+    pub async fn load_decoder(&self) -> Result<EventDecoder> {
+        let abi_file = tokio::fs::read_to_string(&self.abi_path).await?;
+        EventDecoder::from_json(&abi_file)
+    }
 }
@@ -0,0 +1,57 @@
+use ethabi::ethereum_types::U256;
+use mongodb::bson::{doc, Bson, Decimal128};
+use crate::config::NumericMode;
+
+/// Largest number of significant digits a `Decimal128` holds exactly.
+const DECIMAL128_DIGITS: usize = 34;
+
+pub fn uint_to_bson(value: U256, mode: NumericMode) -> Bson {
+    let decimal = value.to_string();
+    match mode {
+        NumericMode::String => Bson::String(decimal),
+        NumericMode::Decimal128 => decimal128_or_string(decimal),
+        NumericMode::Int64 => match i64::try_from(value) {
+            Ok(value) => Bson::Int64(value),
+            Err(_) => decimal128_or_string(decimal),
+        },
+        NumericMode::Dual => dual(decimal, value),
+    }
+}
+
+/// `value` is the two's complement representation ethabi uses for intN.
+pub fn int_to_bson(value: U256, mode: NumericMode) -> Bson {
+    let negative = value.bit(255);
+    let magnitude = if negative { (!value).overflowing_add(U256::one()).0 } else { value };
+    let decimal = if negative { format!("-{}", magnitude) } else { magnitude.to_string() };
+
+    match mode {
+        NumericMode::String => Bson::String(decimal),
+        NumericMode::Decimal128 => decimal128_or_string(decimal),
+        NumericMode::Int64 => match i64::try_from(magnitude) {
+            Ok(magnitude) if negative => Bson::Int64(-magnitude),
+            Ok(magnitude) => Bson::Int64(magnitude),
+            // i64::MIN has no positive counterpart
+            Err(_) if negative && magnitude == U256::from(i64::MAX as u64 + 1) => Bson::Int64(i64::MIN),
+            Err(_) => decimal128_or_string(decimal),
+        },
+        // Flipping the sign bit orders negative values before positive ones.
+        NumericMode::Dual => dual(decimal, value ^ (U256::one() << 255)),
+    }
+}
+
+fn decimal128_or_string(decimal: String) -> Bson {
+    let digits = decimal.trim_start_matches('-').len();
+    if digits <= DECIMAL128_DIGITS {
+        if let Ok(value) = decimal.parse::<Decimal128>() {
+            return Bson::Decimal128(value);
+        }
+    }
+    Bson::String(decimal)
+}
+
+fn dual(decimal: String, sortable: U256) -> Bson {
+    Bson::Document(doc! {
+        "value": decimal,
+        "sortable": format!("{:064x}", sortable),
+    })
+}
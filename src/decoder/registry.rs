@@ -0,0 +1,186 @@
+use dashmap::DashMap;
+use mongodb::bson::{Bson, Document};
+use std::str::FromStr;
+use std::sync::Arc;
+use web3::types::{Log, H160};
+use crate::config::ContractConfig;
+use crate::db::models::DiscoveredContract;
+use crate::decoder::{abi::EventDecoder, DecoderConfig};
+use crate::error::{Error, Result};
+
+/// How a factory contract announces the contracts it creates: `parameter` of
+/// `event` holds the address of a child decoded with `decoder`.
+#[derive(Clone)]
+pub struct ChildTemplate {
+    pub event: String,
+    pub parameter: String,
+    pub name: String,
+    pub decoder: EventDecoder,
+}
+
+#[derive(Clone)]
+pub struct RegisteredContract {
+    pub name: String,
+    pub address: H160,
+    pub start_block: Option<u64>,
+    pub decoder: EventDecoder,
+    /// Factory that created this contract, when it was discovered dynamically.
+    pub factory: Option<H160>,
+    pub children: Option<ChildTemplate>,
+}
+
+/// Contracts indexed on a chain, keyed by address so that each log is routed
+/// to the decoder of the contract that emitted it.
+#[derive(Clone, Default)]
+pub struct ContractRegistry {
+    contracts: Arc<DashMap<H160, RegisteredContract>>,
+}
+
+impl ContractRegistry {
+    pub async fn load(contracts: &[ContractConfig]) -> Result<Self> {
+        let registry = Self::default();
+
+        for contract in contracts {
+            tracing::debug!("Loading ABI of contract {} from {}", contract.name, contract.abi_path);
+            let address = H160::from_str(&contract.address).map_err(|_| Error::InvalidAddress)?;
+            let decoder = DecoderConfig::from_path(&contract.abi_path)?
+                .load_decoder()
+                .await?
+                .with_numeric(contract.numeric.clone());
+
+            let children = match &contract.factory {
+                Some(factory) => {
+                    tracing::debug!("Loading ABI of {} children from {}", contract.name, factory.child_abi_path);
+                    let child_decoder = DecoderConfig::from_path(&factory.child_abi_path)?
+                        .load_decoder()
+                        .await?
+                        .with_numeric(contract.numeric.clone());
+                    Some(ChildTemplate {
+                        event: factory.event.clone(),
+                        parameter: factory.parameter.clone(),
+                        name: factory.child_name.clone(),
+                        decoder: child_decoder,
+                    })
+                }
+                None => None,
+            };
+
+            registry.register(RegisteredContract {
+                name: contract.name.clone(),
+                address,
+                start_block: contract.start_block,
+                decoder,
+                factory: None,
+                children,
+            });
+        }
+
+        Ok(registry)
+    }
+
+    pub fn register(&self, contract: RegisteredContract) {
+        self.contracts.insert(contract.address, contract);
+    }
+
+    /// Registers children persisted by a previous run. Children whose factory
+    /// is no longer configured are skipped.
+    pub fn restore(&self, discovered: &[DiscoveredContract]) -> Result<()> {
+        for contract in discovered {
+            let factory = H160::from_str(&contract.factory_address).map_err(|_| Error::InvalidAddress)?;
+            let address = H160::from_str(&contract.address).map_err(|_| Error::InvalidAddress)?;
+
+            if self.register_child(factory, address, contract.start_block).is_none() {
+                tracing::warn!(
+                    "Ignoring discovered contract {} whose factory {} is not configured",
+                    contract.address,
+                    contract.factory_address
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Registers the child announced by a decoded factory event, returning it
+    /// when the address was not indexed yet.
+    pub fn discover_child(&self, log: &Log, event_name: &str, params: &Document) -> Option<RegisteredContract> {
+        let parameter = {
+            let factory = self.contracts.get(&log.address)?;
+            let template = factory.children.as_ref()?;
+            if template.event != event_name {
+                return None;
+            }
+            template.parameter.clone()
+        };
+
+        let address = match params.get(&parameter) {
+            Some(Bson::String(address)) => H160::from_str(address).ok(),
+            _ => None,
+        };
+
+        let address = match address {
+            Some(address) => address,
+            None => {
+                tracing::warn!(
+                    "Factory event {} of {:?} has no address in parameter {}",
+                    event_name,
+                    log.address,
+                    parameter
+                );
+                return None;
+            }
+        };
+
+        let creation_block = log.block_number.unwrap_or_default().as_u64();
+        let child = self.register_child(log.address, address, creation_block)?;
+        tracing::info!(
+            "Discovered contract {} at {:?} created by factory {:?} in block {}",
+            child.name,
+            child.address,
+            log.address,
+            creation_block
+        );
+        Some(child)
+    }
+
+    fn register_child(&self, factory: H160, address: H160, start_block: u64) -> Option<RegisteredContract> {
+        if self.contracts.contains_key(&address) {
+            return None;
+        }
+
+        let template = self.contracts.get(&factory)?.children.clone()?;
+        let child = RegisteredContract {
+            name: template.name,
+            address,
+            start_block: Some(start_block),
+            decoder: template.decoder,
+            factory: Some(factory),
+            children: None,
+        };
+
+        self.register(child.clone());
+        Some(child)
+    }
+
+    pub fn addresses(&self) -> Vec<H160> {
+        self.contracts.iter().map(|entry| *entry.key()).collect()
+    }
+
+    pub fn contracts(&self) -> Vec<RegisteredContract> {
+        self.contracts.iter().map(|entry| entry.value().clone()).collect()
+    }
+
+    /// Decodes `log` with the ABI of the contract at `log.address`.
+    pub fn decode_log(&self, log: &Log) -> Result<(String, Document)> {
+        let contract = self.contracts
+            .get(&log.address)
+            .ok_or(Error::UnknownContract(log.address))?;
+
+        let raw_log = ethabi::RawLog {
+            topics: log.topics.clone(),
+            data: log.data.0.clone(),
+        };
+
+        contract.decoder.decode_log(raw_log)
+    }
+}
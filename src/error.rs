@@ -26,9 +26,18 @@ pub enum Error {
     #[error("Circuit breaker open")]
     CircuitBreakerOpen,
 
+    #[error("Request timed out")]
+    Timeout,
+
+    #[error("No quorum of endpoints agreed on {0}")]
+    QuorumNotReached(String),
+
     #[error("Unknown event")]
     UnknownEvent,
 
+    #[error("Failed to decode event {0}: {1}")]
+    EventDecodeError(String, String),
+
     #[error("Recovery failed")]
     RecoveryFailed,
 
@@ -47,6 +56,9 @@ pub enum Error {
     #[error("Storage error: {0}")]
     StorageError(String),
 
+    #[error("Write buffer {0} is full")]
+    WriteBufferFull(String),
+
     #[error("Subscription error: {0}")]
     SubscriptionError(String),
 
@@ -67,6 +79,40 @@ pub enum Error {
 
     #[error("Invalid RPC type specified")]
     InvalidRpcType,
+
+    #[error("No contract registered at address {0:?}")]
+    UnknownContract(web3::types::H160),
+
+    #[error("Block not found: {0:?}")]
+    BlockNotFound(web3::types::H256),
+}
+
+impl Error {
+    /// Whether the error means the endpoint could not serve the call, as
+    /// opposed to a JSON-RPC error returned by a working node.
+    pub fn is_endpoint_failure(&self) -> bool {
+        match self {
+            Error::Web3Error(web3::Error::Rpc(_)) => false,
+            Error::Web3Error(_) | Error::Timeout | Error::NotConnected | Error::SubscriptionError(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the log itself cannot be decoded, so that retrying it would
+    /// fail the same way.
+    pub fn is_decode_failure(&self) -> bool {
+        matches!(self, Error::UnknownEvent | Error::EventDecodeError(..) | Error::UnknownContract(_))
+    }
+
+    /// Label of the `indexer_events_decode_failures` metric for this error.
+    pub fn decode_failure_reason(&self) -> &'static str {
+        match self {
+            Error::UnknownEvent => "unknown_event",
+            Error::EventDecodeError(..) => "invalid_data",
+            Error::UnknownContract(_) => "unknown_contract",
+            _ => "decode_error",
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
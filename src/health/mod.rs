@@ -1,4 +1,7 @@
+use crate::chain::connection::TransportCache;
+use crate::circuit_breaker::CircuitBreakers;
 use crate::config::RpcEndpoint;
+use crate::error::{Error, Result};
 use crate::metrics::MetricsCollector;
 use dashmap::DashMap;
 use std::sync::Arc;
@@ -7,6 +10,7 @@ use std::time::{Duration, Instant};
 pub struct HealthCheck {
     metrics: MetricsCollector,
     endpoint_states: Arc<DashMap<String, EndpointHealth>>,
+    clients: TransportCache,
 }
 
 #[derive(Clone, Debug)]
@@ -16,6 +20,7 @@ pub struct EndpointHealth {
     pub latency: Duration,
     pub block_height: u64,
     pub peer_count: u64,
+    pub blocks_behind: u64,
 }
 
 impl HealthCheck {
@@ -23,66 +28,104 @@ impl HealthCheck {
         Self {
             metrics,
             endpoint_states: Arc::new(DashMap::new()),
+            clients: TransportCache::default(),
         }
     }
 
+    /// Probes `endpoint` over its configured transport, failing the check when
+    /// it does not answer within `timeout_secs`. The client is kept between
+    /// checks and only re-created after a failure.
     pub async fn check_endpoint(&self, endpoint: &RpcEndpoint) -> bool {
         let start = Instant::now();
-        let transport = match web3::transports::WebSocket::new(&endpoint.url).await {
-            Ok(ws) => ws,
-            Err(_) => {
-                self.record_failure(&endpoint.url);
-                return false;
-            }
+        let timeout = Duration::from_secs(endpoint.health_check.timeout_secs);
+
+        let probe = match tokio::time::timeout(timeout, self.probe(endpoint)).await {
+            Ok(probe) => probe,
+            Err(_) => Err(Error::Timeout),
         };
 
-        let web3 = web3::Web3::new(transport);
-        let (block_number, peer_count) = match tokio::join!(
-            web3.eth().block_number(),
-            web3.net().peer_count(),
-        ) {
-            (Ok(block), Ok(peers)) => (block, peers),
-            _ => {
+        match probe {
+            Ok((block_number, peer_count)) => {
+                self.record_success(endpoint, start.elapsed(), block_number, peer_count)
+            }
+            Err(e) => {
+                tracing::warn!("Health check of {} failed: {}", endpoint.url, e);
+                self.clients.evict(&endpoint.url);
                 self.record_failure(&endpoint.url);
-                return false;
+                false
             }
-        };
+        }
+    }
+
+    async fn probe(&self, endpoint: &RpcEndpoint) -> Result<(u64, u64)> {
+        let transport = self.clients.get(endpoint).await?;
 
-        let duration = start.elapsed();
-        self.record_success(&endpoint.url, duration, block_number.as_u64(), peer_count.as_u64());
-        true
+        let (block_number, peer_count) = tokio::join!(transport.block_number(), transport.peer_count());
+        Ok((block_number?.as_u64(), peer_count?.as_u64()))
     }
 
-    fn record_success(&self, url: &str, latency: Duration, block_height: u64, peer_count: u64) {
-        self.metrics.record_latency(latency);
+    /// Records a successful probe and evaluates it against the best head known
+    /// across the endpoints of the chain: an endpoint with fewer than
+    /// `min_peers` peers or more than `max_blocks_behind` blocks of lag is
+    /// marked unhealthy even though it answered.
+    fn record_success(&self, endpoint: &RpcEndpoint, latency: Duration, block_height: u64, peer_count: u64) -> bool {
+        let metrics = self.metrics.for_endpoint(&endpoint.url);
+        metrics.record_latency(latency);
+
+        let best_head = self.endpoint_states
+            .iter()
+            .map(|health| health.block_height)
+            .fold(block_height, u64::max);
+        let blocks_behind = best_head - block_height;
+        metrics.set_blocks_behind(blocks_behind);
+
+        let config = &endpoint.health_check;
+        let is_healthy = peer_count >= config.min_peers as u64 && blocks_behind <= config.max_blocks_behind;
+        if !is_healthy {
+            tracing::warn!(
+                "Endpoint {} is unhealthy: {} peers (min {}), {} blocks behind (max {})",
+                endpoint.url,
+                peer_count,
+                config.min_peers,
+                blocks_behind,
+                config.max_blocks_behind
+            );
+        }
+
         self.endpoint_states.insert(
-            url.to_string(),
+            endpoint.url.clone(),
             EndpointHealth {
                 last_checked: Instant::now(),
-                is_healthy: true,
+                is_healthy,
                 latency,
                 block_height,
                 peer_count,
+                blocks_behind,
             },
         );
+
+        is_healthy
     }
 
     fn record_failure(&self, url: &str) {
-        self.metrics.record_failure();
+        self.metrics.for_endpoint(url).record_failure();
         if let Some(mut health) = self.endpoint_states.get_mut(url) {
             health.is_healthy = false;
         }
     }
 
+    /// Health of `url` at its last check; endpoints never checked count as healthy.
+    pub fn is_healthy(&self, url: &str) -> bool {
+        self.endpoint_states
+            .get(url)
+            .map(|health| health.is_healthy)
+            .unwrap_or(true)
+    }
+
     pub fn get_best_endpoint(&self, endpoints: &[RpcEndpoint]) -> Option<RpcEndpoint> {
         endpoints
             .iter()
-            .filter(|e| {
-                self.endpoint_states
-                    .get(&e.url)
-                    .map(|h| h.is_healthy)
-                    .unwrap_or(true)
-            })
+            .filter(|e| self.is_healthy(&e.url))
             .min_by_key(|e| {
                 let health = self.endpoint_states.get(&e.url);
                 (
@@ -92,4 +135,40 @@ impl HealthCheck {
             })
             .cloned()
     }
+
+    /// Endpoints whose circuit breaker lets calls through. When every breaker
+    /// is open all endpoints are returned, as a last resort.
+    pub fn available_endpoints(&self, endpoints: &[RpcEndpoint], circuit_breakers: &CircuitBreakers) -> Vec<RpcEndpoint> {
+        let available: Vec<RpcEndpoint> = endpoints
+            .iter()
+            .filter(|endpoint| {
+                circuit_breakers
+                    .get(&endpoint.url)
+                    .is_none_or(|breaker| breaker.can_execute())
+            })
+            .cloned()
+            .collect();
+
+        if available.is_empty() {
+            tracing::warn!("Circuit breakers of every endpoint are open");
+            return endpoints.to_vec();
+        }
+
+        available
+    }
+
+    /// Endpoints in the order they should be tried: the best available one
+    /// according to the health state, then the others by priority.
+    pub fn endpoint_candidates(&self, endpoints: &[RpcEndpoint], circuit_breakers: &CircuitBreakers) -> Vec<RpcEndpoint> {
+        let available = self.available_endpoints(endpoints, circuit_breakers);
+        let best = self.get_best_endpoint(&available);
+
+        let mut others: Vec<RpcEndpoint> = available
+            .into_iter()
+            .filter(|endpoint| best.as_ref().is_none_or(|best| best.url != endpoint.url))
+            .collect();
+        others.sort_by_key(|endpoint| endpoint.priority);
+
+        best.into_iter().chain(others).collect()
+    }
 }
@@ -11,6 +11,7 @@ pub mod sync;
 pub use config::Config;
 pub use db::DatabaseConnection;
 pub use decoder::abi::EventDecoder;
+pub use decoder::registry::ContractRegistry;
 pub use chain::{
     connection::ChainConnection,
     event_listener::EventListener,
@@ -1,5 +1,5 @@
 use evm_indexer::{
-    chain::{connection::ChainConnection, event_listener::EventListener}, config::{Config, RpcType}, db::DatabaseConnection, decoder::{abi::EventDecoder, DecoderConfig}, health::HealthCheck, metrics::MetricsCollector, sync::historical::HistoricalSync, Error};
+    chain::{connection::ChainConnection, endpoint_pool::HttpEndpointPool, event_listener::EventListener, quorum::QuorumReader}, circuit_breaker::CircuitBreaker, config::{ChainConfig, Config}, db::{contracts::DiscoveredContractStore, events::EventStore, DatabaseConnection}, decoder::registry::ContractRegistry, health::HealthCheck, metrics::MetricsCollector, sync::{historical::HistoricalSync, pipeline::SyncPipeline, reindex::Reindexer, reprocess::FailedLogReprocessor}, Error};
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::fmt::writer::MakeWriterExt;
 
@@ -39,6 +39,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let db_connection = DatabaseConnection::new(&config.database).await?;
 
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("reprocess-failed-logs") => return reprocess_failed_logs(config, &db_connection).await,
+        Some("reindex") => return reindex(config, &db_connection, &args[1..]).await,
+        Some("migrate-events") => return migrate_events(config, &db_connection).await,
+        _ => {}
+    }
+
     let metrics_route = warp::path!("metrics").map(|| {
         let encoder = prometheus::TextEncoder::new();
         let metric_families = prometheus::default_registry().gather();
@@ -56,59 +64,47 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let db = db_connection.database.clone();
         let metrics = MetricsCollector::new(&chain_config.name, &chain_config.rpcs[0].url);
         let health_checker = Arc::new(HealthCheck::new(metrics.clone()));
-        tracing::debug!("Loading ABI...");
-        let decoder_config = DecoderConfig::new()?;
-        let contract = decoder_config.load_contract().await?;
-        let decoder = EventDecoder::new(contract);
-        let historical_config = chain_config.clone();
-        let historical_decoder = decoder.clone();
-        let historical_db = db.clone();
-        let historical_metrics = metrics.clone();
-
-        let http_endpoint = chain_config.rpcs.iter()
-        .find(|e| matches!(e.rpc_type, RpcType::Http));
-
-        if let Some(http_endpoint) = http_endpoint {
-            let transport = web3::transports::Http::new(&http_endpoint.url)?;
-            let web3 = web3::Web3::new(transport);
-            let current_block = web3.eth().block_number().await?.as_u64();
-            tracing::info!(
-                "Starting historical sync for {} from block {} to {}",
-                historical_config.name,
-                historical_config.starting_block.unwrap_or(0),
-                current_block
-            );
-            
-            let historical_sync = HistoricalSync::new(
-                web3,
-                historical_config.name,
-                historical_config.contract_address,
-                historical_decoder,
-                historical_db,
-                historical_metrics,
-                1000,
-            );
-        
-            if let Some(starting_block) = historical_config.starting_block {
-                let sync_handle = tokio::spawn(async move {
-                    if let Err(e) = historical_sync.sync_to_block(starting_block, current_block).await {
-                        tracing::error!("Historical sync error: {:?}", e);
-                    } else {
-                        tracing::info!("Historical sync completed up to block {}", current_block);
-                    }
-                });
-                handles.push(sync_handle);
-            }
-        }
+        tracing::debug!("Loading ABIs...");
+        let contracts = ContractRegistry::load(&chain_config.contracts()?).await?;
+        contracts.restore(&DiscoveredContractStore::new(&db).load(&chain_config.name).await?)?;
+        let circuit_breakers = CircuitBreaker::for_chain(&chain_config, &metrics);
+        let endpoints = HttpEndpointPool::new(&chain_config, &circuit_breakers, health_checker.clone())?;
+        let historical_sync = if endpoints.is_empty() {
+            None
+        } else {
+            Some(HistoricalSync::new(
+                endpoints,
+                &chain_config,
+                contracts.clone(),
+                db.clone(),
+                metrics.clone(),
+            ))
+        };
 
         let chain_name = chain_config.name.clone();
 
+        let quorum = match &chain_config.quorum {
+            Some(quorum) => Some(QuorumReader::new(&chain_config, quorum, circuit_breakers.clone(), &db, metrics.clone())?),
+            None => None,
+        };
+
         let connection = ChainConnection::new(
             chain_config.clone(),
             metrics.clone(),
+            health_checker.clone(),
+            circuit_breakers,
+            quorum,
         ).await?;
 
-        let listener = EventListener::new(connection, decoder, db);
+        let listener = EventListener::new(connection, contracts.clone(), db.clone(), &config.database).await?;
+
+        let pipeline = SyncPipeline::new(
+            chain_config.name.clone(),
+            contracts,
+            historical_sync,
+            listener,
+            &db,
+        );
 
         let health_config = chain_config.clone();
         let health_clone = health_checker.clone();
@@ -124,7 +120,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         });
 
         let handle = tokio::spawn(async move {
-            if let Err(e) = listener.start().await {
+            if let Err(e) = pipeline.run().await {
                 tracing::error!("Chain {} error: {:?}", chain_name, e);
             }
         });
@@ -136,3 +132,121 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+/// Retries the logs kept in the `failed_logs` collection of every chain.
+async fn reprocess_failed_logs(config: Config, db_connection: &DatabaseConnection) -> Result<(), Box<dyn std::error::Error>> {
+    for chain_config in config.chains {
+        let db = db_connection.database.clone();
+        let contracts = ContractRegistry::load(&chain_config.contracts()?).await?;
+        contracts.restore(&DiscoveredContractStore::new(&db).load(&chain_config.name).await?)?;
+        let metrics = MetricsCollector::new(&chain_config.name, &chain_config.rpcs[0].url);
+        let circuit_breakers = CircuitBreaker::for_chain(&chain_config, &metrics);
+        let health = Arc::new(HealthCheck::new(metrics.clone()));
+        let connection = ChainConnection::new(chain_config.clone(), metrics, health, circuit_breakers, None).await?;
+
+        let reprocessor = FailedLogReprocessor::new(chain_config.name.clone(), contracts, connection, &db);
+        let report = reprocessor.run().await?;
+        tracing::info!(
+            "Reprocessed failed logs of chain {}: {} stored, {} still failing",
+            chain_config.name,
+            report.reprocessed,
+            report.failed
+        );
+    }
+
+    Ok(())
+}
+
+/// Re-indexes `[from, to]` on one chain: `reindex <chain> <from> <to>`.
+async fn reindex(config: Config, db_connection: &DatabaseConnection, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let usage = || Error::InvalidConfig("Usage: evm-indexer reindex <chain> <from_block> <to_block>".to_string());
+    let (chain_name, from_block, to_block) = match args {
+        [chain_name, from_block, to_block] => (
+            chain_name,
+            from_block.parse::<u64>().map_err(|_| usage())?,
+            to_block.parse::<u64>().map_err(|_| usage())?,
+        ),
+        _ => return Err(usage().into()),
+    };
+    if from_block > to_block {
+        return Err(usage().into());
+    }
+
+    let chain_config = config.chains
+        .into_iter()
+        .find(|chain| &chain.name == chain_name)
+        .ok_or_else(|| Error::InvalidConfig(format!("Unknown chain {}", chain_name)))?;
+
+    let report = reindexer(&chain_config, db_connection)
+        .await?
+        .run(from_block, to_block)
+        .await?;
+
+    tracing::info!(
+        "Reindexed blocks {} to {} of chain {}: {} inserted, {} updated, {} unchanged, {} removed",
+        from_block,
+        to_block,
+        chain_config.name,
+        report.inserted,
+        report.updated,
+        report.unchanged,
+        report.removed
+    );
+
+    Ok(())
+}
+
+/// Upgrades the events stored by a version without log indexes: the blocks
+/// holding them are re-indexed, then the old documents are deleted and the
+/// unique event index is built.
+async fn migrate_events(config: Config, db_connection: &DatabaseConnection) -> Result<(), Box<dyn std::error::Error>> {
+    let events = EventStore::new(&db_connection.database);
+
+    for chain_config in config.chains {
+        let (from_block, to_block) = match events.legacy_block_range(&chain_config.name).await? {
+            Some(range) => range,
+            None => continue,
+        };
+
+        tracing::info!(
+            "Re-indexing blocks {} to {} of chain {} to add log indexes to its events",
+            from_block,
+            to_block,
+            chain_config.name
+        );
+        let report = reindexer(&chain_config, db_connection)
+            .await?
+            .run(from_block, to_block)
+            .await?;
+        // The reindex already removes the old events of the range it covers.
+        let removed = report.removed + events.delete_legacy(&chain_config.name).await?;
+
+        tracing::info!(
+            "Migrated events of chain {}: {} inserted, {} old events removed",
+            chain_config.name,
+            report.inserted,
+            removed
+        );
+    }
+
+    events.ensure_indexes().await?;
+    tracing::info!("Events collection migrated");
+
+    Ok(())
+}
+
+async fn reindexer(chain_config: &ChainConfig, db_connection: &DatabaseConnection) -> Result<Reindexer, Box<dyn std::error::Error>> {
+    let db = db_connection.database.clone();
+    let contracts = ContractRegistry::load(&chain_config.contracts()?).await?;
+    contracts.restore(&DiscoveredContractStore::new(&db).load(&chain_config.name).await?)?;
+
+    let metrics = MetricsCollector::new(&chain_config.name, &chain_config.rpcs[0].url);
+    let health = Arc::new(HealthCheck::new(metrics.clone()));
+    let endpoints = HttpEndpointPool::new(chain_config, &CircuitBreaker::for_chain(chain_config, &metrics), health)?;
+    if endpoints.is_empty() {
+        return Err(Error::NoHealthyEndpoints.into());
+    }
+
+    let historical = HistoricalSync::new(endpoints, chain_config, contracts, db.clone(), metrics);
+    Ok(Reindexer::new(chain_config.name.clone(), historical, &db))
+}
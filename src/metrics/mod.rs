@@ -52,6 +52,46 @@ lazy_static! {
         &["chain", "event_type"]
     ).unwrap();
 
+    static ref CHAIN_REORGS: IntCounterVec = register_int_counter_vec!(
+        opts!("indexer_chain_reorgs", "Number of chain reorganizations detected"),
+        &["chain"]
+    ).unwrap();
+
+    static ref EVENTS_REMOVED: IntCounterVec = register_int_counter_vec!(
+        opts!("indexer_events_removed", "Number of stored events removed after a chain reorganization"),
+        &["chain"]
+    ).unwrap();
+
+    static ref ENDPOINT_BLOCKS_BEHIND: IntGaugeVec = register_int_gauge_vec!(
+        opts!("indexer_endpoint_blocks_behind", "Number of blocks an endpoint is behind the best head known for its chain"),
+        &["chain", "endpoint"]
+    ).unwrap();
+
+    static ref CIRCUIT_BREAKER_STATE: IntGaugeVec = register_int_gauge_vec!(
+        opts!("indexer_circuit_breaker_state", "Circuit breaker state of an endpoint (0 closed, 1 half-open, 2 open)"),
+        &["chain", "endpoint"]
+    ).unwrap();
+
+    static ref CIRCUIT_BREAKER_TRANSITIONS: IntCounterVec = register_int_counter_vec!(
+        opts!("indexer_circuit_breaker_transitions", "Number of circuit breaker state transitions"),
+        &["chain", "endpoint", "from", "to"]
+    ).unwrap();
+
+    static ref WRITE_BUFFER_EVENTS: IntGaugeVec = register_int_gauge_vec!(
+        opts!("indexer_write_buffer_events", "Number of events spooled on disk while MongoDB is unreachable"),
+        &["chain"]
+    ).unwrap();
+
+    static ref QUORUM_DISAGREEMENTS: IntCounterVec = register_int_counter_vec!(
+        opts!("indexer_quorum_disagreements", "Number of cross-checked reads on which endpoints returned different answers"),
+        &["chain", "query"]
+    ).unwrap();
+
+    static ref QUORUM_FAILURES: IntCounterVec = register_int_counter_vec!(
+        opts!("indexer_quorum_failures", "Number of cross-checked reads on which no quorum of endpoints agreed"),
+        &["chain", "query"]
+    ).unwrap();
+
     static ref EVENT_PROCESSING_DURATION: HistogramVec = register_histogram_vec!(
         "indexer_event_processing_duration",
         "Time taken to process events in seconds",
@@ -74,6 +114,11 @@ impl MetricsCollector {
         }
     }
 
+    /// Collector for another endpoint of the same chain.
+    pub fn for_endpoint(&self, endpoint_url: &str) -> Self {
+        Self::new(&self.chain_name, endpoint_url)
+    }
+
     pub fn record_failure(&self) {
         ENDPOINT_FAILURES
             .with_label_values(&[&self.chain_name, &self.endpoint_url])
@@ -110,6 +155,36 @@ impl MetricsCollector {
             .inc();
     }
 
+    pub fn set_circuit_breaker_state(&self, level: i64) {
+        CIRCUIT_BREAKER_STATE
+            .with_label_values(&[&self.chain_name, &self.endpoint_url])
+            .set(level);
+    }
+
+    pub fn record_circuit_breaker_transition(&self, from: &str, to: &str) {
+        CIRCUIT_BREAKER_TRANSITIONS
+            .with_label_values(&[&self.chain_name, &self.endpoint_url, from, to])
+            .inc();
+    }
+
+    pub fn set_write_buffer_events(&self, count: usize) {
+        WRITE_BUFFER_EVENTS
+            .with_label_values(&[&self.chain_name])
+            .set(count as i64);
+    }
+
+    pub fn record_quorum_disagreement(&self, query: &str) {
+        QUORUM_DISAGREEMENTS
+            .with_label_values(&[&self.chain_name, query])
+            .inc();
+    }
+
+    pub fn record_quorum_failure(&self, query: &str) {
+        QUORUM_FAILURES
+            .with_label_values(&[&self.chain_name, query])
+            .inc();
+    }
+
     pub fn record_event_received(&self) {
         EVENTS_RECEIVED
             .with_label_values(&[&self.chain_name])
@@ -133,4 +208,22 @@ impl MetricsCollector {
             .with_label_values(&[&self.chain_name, event_type])
             .observe(duration);
     }
+
+    pub fn record_reorg(&self) {
+        CHAIN_REORGS
+            .with_label_values(&[&self.chain_name])
+            .inc();
+    }
+
+    pub fn record_events_removed(&self, count: u64) {
+        EVENTS_REMOVED
+            .with_label_values(&[&self.chain_name])
+            .inc_by(count);
+    }
+
+    pub fn set_blocks_behind(&self, blocks: u64) {
+        ENDPOINT_BLOCKS_BEHIND
+            .with_label_values(&[&self.chain_name, &self.endpoint_url])
+            .set(blocks as i64);
+    }
 }
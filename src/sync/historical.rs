@@ -1,136 +1,364 @@
 use crate::error::{Error, Result};
+use crate::config::{ChainConfig, LogRangeConfig};
+use crate::chain::block_timestamps::BlockTimestamps;
+use crate::chain::endpoint_pool::HttpEndpointPool;
+use crate::db::checkpoint::CheckpointStore;
+use crate::db::contracts::DiscoveredContractStore;
+use crate::db::events::EventStore;
+use crate::db::failed_logs::FailedLogStore;
 use crate::db::models::EventLog;
-use crate::decoder::abi::EventDecoder;
+use crate::decoder::registry::{ContractRegistry, RegisteredContract};
 use crate::metrics::MetricsCollector;
+use futures::stream::{FuturesOrdered, StreamExt};
+use mongodb::bson::DateTime;
 use mongodb::Database;
-use web3::{
-    types::{BlockNumber, FilterBuilder, Log, H160},
-    Transport, Web3,
-};
-use std::str::FromStr;
-use mongodb::bson::{doc, Bson};
-
-pub struct HistoricalSync<T: Transport> {
-    web3: Web3<T>,
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc;
+use web3::types::{BlockId, BlockNumber, FilterBuilder, Log, H160, H256};
+
+/// A fetched range: first and last block, the range size it was scheduled
+/// with and its logs.
+type FetchedRange = (u64, u64, u64, Result<Vec<Log>>);
+
+/// How a historical pass writes the events it fetches.
+#[derive(Clone, Copy, PartialEq)]
+enum SyncMode {
+    /// Events already stored are left untouched and checkpoints follow the sync.
+    Backfill,
+    /// Stored events are overwritten and checkpoints, which may already be
+    /// past the range, are left alone.
+    Reindex,
+}
+
+pub struct HistoricalSync {
+    endpoints: HttpEndpointPool,
     chain_name: String,
-    contract_address: String,
-    decoder: EventDecoder,
-    db: Database,
+    contracts: ContractRegistry,
+    events: EventStore,
     metrics: MetricsCollector,
-    batch_size: u64,
+    checkpoints: CheckpointStore,
+    discovered: DiscoveredContractStore,
+    failed_logs: FailedLogStore,
+    block_timestamps: BlockTimestamps,
+    log_range: LogRangeConfig,
+    range_size: AtomicU64,
+    workers: usize,
+    detect_start_block: bool,
 }
 
-impl<T: Transport> HistoricalSync<T> {
+/// Fragments of the errors providers return when an `eth_getLogs` range
+/// holds too many blocks or results.
+const RANGE_LIMIT_ERRORS: &[&str] = &[
+    "query returned more than",
+    "block range too large",
+    "block range is too large",
+    "range is too large",
+    "exceed maximum block range",
+    "response size exceeded",
+    "too many results",
+];
+
+impl HistoricalSync {
     pub fn new(
-        web3: Web3<T>,
-        chain_name: String,
-        contract_address: String,
-        decoder: EventDecoder,
+        endpoints: HttpEndpointPool,
+        config: &ChainConfig,
+        contracts: ContractRegistry,
         db: Database,
         metrics: MetricsCollector,
-        batch_size: u64,
     ) -> Self {
+        let checkpoints = CheckpointStore::new(&db);
+        let log_range = config.log_range.clone();
         Self {
-            web3,
-            chain_name,
-            contract_address,
-            decoder,
-            db,
+            endpoints,
+            chain_name: config.name.clone(),
+            contracts,
+            events: EventStore::new(&db),
             metrics,
-            batch_size,
+            checkpoints,
+            discovered: DiscoveredContractStore::new(&db),
+            failed_logs: FailedLogStore::new(&db),
+            // Large enough to hold the timestamps of every range in flight.
+            block_timestamps: BlockTimestamps::new(log_range.max_blocks as usize * config.historical_workers.max(1)),
+            range_size: AtomicU64::new(log_range.initial_blocks.min(log_range.max_blocks).max(log_range.min_blocks).max(1)),
+            log_range,
+            workers: config.historical_workers.max(1),
+            detect_start_block: config.detect_start_block,
         }
     }
 
+    /// Syncs `[from_block, to_block]`. Up to `workers` ranges are fetched
+    /// concurrently, but they are stored and checkpointed strictly in order.
     pub async fn sync_to_block(&self, from_block: u64, to_block: u64) -> Result<()> {
-        let mut current_block = from_block;
-
-        while current_block < to_block {
-            let end_block = std::cmp::min(current_block + self.batch_size, to_block);
-            
-            tracing::info!(
-                "Processing historical blocks {} to {} for chain {}",
-                current_block,
-                end_block,
-                self.chain_name
-            );
-
-            let logs = self.fetch_logs_batch(current_block, end_block).await?;
-            self.process_logs(logs).await?;
-
-            current_block = end_block + 1;
-            self.metrics.update_block_height(current_block);
+        self.sync(from_block, to_block, SyncMode::Backfill).await
+    }
+
+    /// Syncs `[from_block, to_block]` again, overwriting the stored events and
+    /// leaving the checkpoints alone.
+    pub async fn resync_range(&self, from_block: u64, to_block: u64) -> Result<()> {
+        self.sync(from_block, to_block, SyncMode::Reindex).await
+    }
+
+    /// Stores the ranges handed over by `fetch_ranges`, which runs alongside
+    /// so that fetches keep progressing while a range is being stored.
+    async fn sync(&self, from_block: u64, to_block: u64, mode: SyncMode) -> Result<()> {
+        let (sender, mut receiver) = mpsc::channel::<FetchedRange>(1);
+        let scheduled_to = AtomicU64::new(from_block);
+        let scheduled = &scheduled_to;
+
+        let store = async move {
+            while let Some((current_block, end_block, range_size, logs)) = receiver.recv().await {
+                let logs = logs?;
+                if logs.len() < self.log_range.sparse_logs && self.range_size.load(Ordering::Relaxed) == range_size {
+                    self.grow_range();
+                }
+                let mut children = self.process_logs(logs, mode).await?;
+
+                // Contracts created by a factory within this batch are backfilled
+                // from their creation block before the checkpoint moves past it.
+                // Ranges scheduled before they were discovered do not cover them,
+                // so the backfill has to reach the last scheduled block.
+                while !children.is_empty() {
+                    let backfill_to = std::cmp::max(end_block, scheduled.load(Ordering::SeqCst));
+                    let mut discovered = Vec::new();
+                    for child in children {
+                        let creation_block = child.start_block.unwrap_or(current_block);
+                        let logs = self.fetch_logs_batch(creation_block, backfill_to, vec![child.address]).await?;
+                        discovered.extend(self.process_logs(logs, mode).await?);
+                    }
+                    children = discovered;
+                }
+
+                if mode == SyncMode::Backfill {
+                    self.save_checkpoint(end_block).await?;
+                    self.metrics.update_block_height(end_block + 1);
+                }
+            }
+
+            Ok(())
+        };
+
+        let (result, ()) = tokio::join!(store, self.fetch_ranges(from_block, to_block, scheduled, sender));
+        result
+    }
+
+    /// Fetches `[from_block, to_block]` with up to `workers` ranges in flight
+    /// and hands them over in order. Stops early once `sender` is closed.
+    async fn fetch_ranges(&self, from_block: u64, to_block: u64, scheduled_to: &AtomicU64, sender: mpsc::Sender<FetchedRange>) {
+        let mut in_flight = FuturesOrdered::new();
+        let mut ready = VecDeque::new();
+        let mut next_block = from_block;
+
+        loop {
+            while in_flight.len() + ready.len() < self.workers && next_block <= to_block {
+                let range_size = self.range_size.load(Ordering::Relaxed);
+                let end_block = std::cmp::min(next_block + range_size - 1, to_block);
+                in_flight.push_back(self.fetch_range(next_block, end_block, range_size));
+                scheduled_to.store(end_block, Ordering::SeqCst);
+                next_block = end_block + 1;
+            }
+            if in_flight.is_empty() && ready.is_empty() {
+                break;
+            }
+
+            tokio::select! {
+                Some(range) = in_flight.next(), if !in_flight.is_empty() => ready.push_back(range),
+                permit = sender.reserve(), if !ready.is_empty() => match (permit, ready.pop_front()) {
+                    (Ok(permit), Some(range)) => permit.send(range),
+                    _ => break,
+                },
+            }
+        }
+    }
+
+    /// Block in which the contract at `address` was deployed, found by binary
+    /// search over `eth_getCode`. `None` when detection is disabled or there is
+    /// no code at `head`.
+    pub async fn deployment_block(&self, address: H160, head: u64) -> Result<Option<u64>> {
+        if !self.detect_start_block || !self.has_code(address, head).await? {
+            return Ok(None);
         }
 
-        Ok(())
+        let (mut low, mut high) = (0, head);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.has_code(address, middle).await? {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+
+        Ok(Some(low))
     }
 
-    async fn fetch_logs_batch(&self, from_block: u64, to_block: u64) -> Result<Vec<Log>> {
-        let contract = H160::from_str(&self.contract_address)
-            .map_err(|_| Error::InvalidAddress)?;
+    async fn has_code(&self, address: H160, block_number: u64) -> Result<bool> {
+        let block = Some(BlockNumber::Number(block_number.into()));
+        let code = self.endpoints
+            .execute(|web3| async move { web3.eth().code(address, block).await })
+            .await?;
 
+        Ok(!code.0.is_empty())
+    }
+
+    /// Fetches the logs of a range along with the timestamps of their blocks,
+    /// which is the part of a range worth running concurrently.
+    async fn fetch_range(&self, from_block: u64, to_block: u64, range_size: u64) -> (u64, u64, u64, Result<Vec<Log>>) {
+        tracing::info!(
+            "Processing historical blocks {} to {} for chain {}",
+            from_block,
+            to_block,
+            self.chain_name
+        );
+
+        let logs = match self.fetch_logs_batch(from_block, to_block, self.contracts.addresses()).await {
+            Ok(logs) => logs,
+            Err(e) => return (from_block, to_block, range_size, Err(e)),
+        };
+
+        for log in &logs {
+            if let Err(e) = self.block_timestamp(log.block_hash.unwrap_or_default()).await {
+                return (from_block, to_block, range_size, Err(e));
+            }
+        }
+
+        (from_block, to_block, range_size, Ok(logs))
+    }
+
+    async fn block_timestamp(&self, block_hash: H256) -> Result<DateTime> {
+        self.block_timestamps
+            .get_or_fetch(block_hash, || {
+                self.endpoints.execute(|web3| async move { web3.eth().block(BlockId::Hash(block_hash)).await })
+            })
+            .await
+    }
+
+    async fn save_checkpoint(&self, block_number: u64) -> Result<()> {
+        let block_id = BlockId::Number(BlockNumber::Number(block_number.into()));
+        let block_hash = self.endpoints
+            .execute(|web3| async move { web3.eth().block(block_id).await })
+            .await?
+            .and_then(|block| block.hash)
+            .unwrap_or_else(H256::zero);
+
+        self.checkpoints
+            .save_all(&self.chain_name, &self.contracts.addresses(), block_number, block_hash)
+            .await
+    }
+
+    /// Fetches the logs of `contracts` in `[from_block, to_block]`, bisecting
+    /// the range whenever the provider rejects it as too large.
+    async fn fetch_logs_batch(&self, from_block: u64, to_block: u64, contracts: Vec<H160>) -> Result<Vec<Log>> {
+        let mut logs = Vec::new();
+        let mut pending = vec![(from_block, to_block)];
+
+        while let Some((from_block, to_block)) = pending.pop() {
+            let blocks = to_block - from_block + 1;
+            match self.fetch_logs(from_block, to_block, contracts.clone()).await {
+                Ok(batch) => logs.extend(batch),
+                Err(e) if blocks > self.log_range.min_blocks && Self::is_range_limit(&e) => {
+                    let middle = from_block + blocks / 2 - 1;
+                    self.shrink_range(blocks / 2);
+                    tracing::warn!(
+                        "Range {} to {} rejected on {}, splitting at block {}: {}",
+                        from_block,
+                        to_block,
+                        self.chain_name,
+                        middle,
+                        e
+                    );
+                    pending.push((middle + 1, to_block));
+                    pending.push((from_block, middle));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(logs)
+    }
+
+    async fn fetch_logs(&self, from_block: u64, to_block: u64, contracts: Vec<H160>) -> Result<Vec<Log>> {
         let filter = FilterBuilder::default()
-            .address(vec![contract])
+            .address(contracts)
             .from_block(BlockNumber::Number(from_block.into()))
             .to_block(BlockNumber::Number(to_block.into()))
             .build();
 
-        self.web3
-            .eth()
-            .logs(filter)
+        self.endpoints
+            .execute(|web3| {
+                let filter = filter.clone();
+                async move { web3.eth().logs(filter).await }
+            })
             .await
-            .map_err(Error::Web3Error)
     }
 
-    async fn process_logs(&self, logs: Vec<Log>) -> Result<()> {
+    fn is_range_limit(error: &Error) -> bool {
+        let message = error.to_string().to_lowercase();
+        RANGE_LIMIT_ERRORS.iter().any(|fragment| message.contains(fragment))
+    }
+
+    fn shrink_range(&self, blocks: u64) {
+        let blocks = blocks.max(self.log_range.min_blocks).max(1);
+        self.range_size.fetch_min(blocks, Ordering::Relaxed);
+    }
+
+    fn grow_range(&self) {
+        let blocks = self.range_size.load(Ordering::Relaxed);
+        let grown = blocks.saturating_mul(2).min(self.log_range.max_blocks);
+        if grown > blocks {
+            tracing::debug!("Growing log range of {} to {} blocks", self.chain_name, grown);
+            self.range_size.store(grown, Ordering::Relaxed);
+        }
+    }
+
+    /// Decodes `logs` in memory and stores them, with one bulk write when
+    /// backfilling, returning the child contracts they announced.
+    async fn process_logs(&self, logs: Vec<Log>, mode: SyncMode) -> Result<Vec<RegisteredContract>> {
+        let mut children = Vec::new();
+        let mut event_logs = Vec::with_capacity(logs.len());
+        let mut event_names = Vec::with_capacity(logs.len());
+
         for log in logs {
-            let exists = self
-                .db
-                .collection::<EventLog>("events")
-                .find_one(
-                    doc! {
-                        "transaction_hash": format!("{:?}", log.transaction_hash.unwrap_or_default()),
-                        "block_number": Bson::Int64(log.block_number.unwrap_or_default().as_u64() as i64)
-                    },
-                    None,
-                )
-                .await?
-                .is_some();
-
-            if !exists {
-                self.metrics.record_event_received();
-                
-                let raw_log = ethabi::RawLog {
-                    topics: log.topics.clone(),
-                    data: log.data.0.clone(),
-                };
-
-                match self.decoder.decode_log(raw_log) {
-                    Ok((event_name, params)) => {
-                        let event_log = EventLog {
-                            chain_name:  self.chain_name.clone(),
-                            event_name: event_name.clone(),
-                            block_number: log.block_number.unwrap_or_default().as_u64(),
-                            transaction_hash: format!("{:?}", log.transaction_hash.unwrap_or_default()),
-                            params,
-                            timestamp: mongodb::bson::DateTime::now(),
-                        };
-
-                        self.db
-                            .collection("events")
-                            .insert_one(event_log, None)
-                            .await?;
-
-                        self.metrics.record_event_by_type(&event_name);
-                        self.metrics.record_event_processed(&event_name);
-                    }
-                    Err(e) => {
-                        self.metrics.record_event_decode_failure("decode_error");
-                        tracing::error!("Failed to decode historical on {} event: {:?}", self.chain_name.clone(), e);
+            self.metrics.record_event_received();
+
+            match self.contracts.decode_log(&log) {
+                Ok((event_name, params)) => {
+                    if let Some(child) = self.contracts.discover_child(&log, &event_name, &params) {
+                        self.discovered.save(&self.chain_name, &child).await?;
+                        children.push(child);
                     }
+
+                    let block_timestamp = self.block_timestamp(log.block_hash.unwrap_or_default()).await?;
+
+                    event_logs.push(EventLog::new(&self.chain_name, &event_name, params, &log, block_timestamp));
+                    event_names.push(event_name);
+                }
+                Err(e) => {
+                    self.metrics.record_event_decode_failure(e.decode_failure_reason());
+                    tracing::error!("Failed to decode historical on {} event: {:?}", self.chain_name.clone(), e);
+                    self.failed_logs.record(&self.chain_name, &log, &e).await?;
                 }
             }
         }
 
-        Ok(())
+        match mode {
+            SyncMode::Backfill => {
+                let inserted = self.events.insert_many(&event_logs).await?;
+                tracing::debug!(
+                    "Stored {} new of {} decoded events on {}",
+                    inserted,
+                    event_logs.len(),
+                    self.chain_name
+                );
+            }
+            SyncMode::Reindex => self.events.upsert_many(&event_logs).await?,
+        }
+
+        for event_name in &event_names {
+            self.metrics.record_event_by_type(event_name);
+            self.metrics.record_event_processed(event_name);
+        }
+
+        Ok(children)
     }
 }
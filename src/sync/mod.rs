@@ -1 +1,4 @@
 pub mod historical;
+pub mod pipeline;
+pub mod reindex;
+pub mod reprocess;
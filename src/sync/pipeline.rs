@@ -0,0 +1,193 @@
+use crate::chain::event_listener::EventListener;
+use crate::db::checkpoint::CheckpointStore;
+use crate::decoder::registry::{ContractRegistry, RegisteredContract};
+use crate::error::Result;
+use crate::sync::historical::HistoricalSync;
+use backoff::ExponentialBackoff;
+use mongodb::Database;
+use std::future::Future;
+use std::time::Duration;
+
+/// Once a historical pass covers fewer blocks than this, the remaining delta
+/// is small enough to be picked up by the live listener itself.
+const CATCH_UP_THRESHOLD: u64 = 100;
+
+/// Longest wait between two attempts of a failing read or historical pass.
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Drives a chain through backfill, catch-up and live listening so that every
+/// block is processed exactly once, with an explicit boundary between modes.
+pub struct SyncPipeline {
+    chain_name: String,
+    contracts: ContractRegistry,
+    historical: Option<HistoricalSync>,
+    listener: EventListener,
+    checkpoints: CheckpointStore,
+}
+
+impl SyncPipeline {
+    pub fn new(
+        chain_name: String,
+        contracts: ContractRegistry,
+        historical: Option<HistoricalSync>,
+        listener: EventListener,
+        db: &Database,
+    ) -> Self {
+        Self {
+            chain_name,
+            contracts,
+            historical,
+            listener,
+            checkpoints: CheckpointStore::new(db),
+        }
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let mut next_block = self.retry("Reading the resume block", || self.resume_block()).await?;
+
+        if let (Some(historical), Some(from_block)) = (&self.historical, next_block) {
+            let mut from_block = from_block;
+            loop {
+                let head = self.confirmed_head().await?;
+                if from_block > head {
+                    break;
+                }
+
+                tracing::info!(
+                    "Starting historical sync for {} from block {} to {}",
+                    self.chain_name,
+                    from_block,
+                    head
+                );
+                self.backfill(historical, from_block, head).await?;
+                tracing::info!("Historical sync completed up to block {}", head);
+
+                let synced = head - from_block + 1;
+                from_block = head + 1;
+                if synced <= CATCH_UP_THRESHOLD {
+                    break;
+                }
+            }
+            next_block = Some(from_block);
+        }
+
+        let boundary = match next_block {
+            Some(block) => block,
+            None => self.confirmed_head().await? + 1,
+        };
+
+        tracing::info!("Switching chain {} to live mode at block {}", self.chain_name, boundary);
+        self.listener.start(boundary).await
+    }
+
+    /// First block that still needs to be indexed: the lowest block after a
+    /// contract's persisted checkpoint, or its `start_block` when it has none.
+    /// Contracts without either are backfilled from their deployment block
+    /// when `detect_start_block` is enabled.
+    async fn resume_block(&self) -> Result<Option<u64>> {
+        let mut resume_block: Option<u64> = None;
+
+        for contract in self.contracts.contracts() {
+            let checkpoint = self.checkpoints
+                .load(&self.chain_name, &format!("{:?}", contract.address))
+                .await?;
+
+            let contract_block = match checkpoint {
+                Some(checkpoint) => {
+                    tracing::info!(
+                        "Resuming contract {} on chain {} from checkpoint at block {} ({})",
+                        contract.name,
+                        self.chain_name,
+                        checkpoint.block_number,
+                        checkpoint.block_hash
+                    );
+                    Some(checkpoint.block_number + 1)
+                }
+                None => match (contract.start_block, &self.historical) {
+                    (Some(block), _) => Some(block),
+                    (None, Some(historical)) => self.detect_start_block(historical, contract).await?,
+                    (None, None) => None,
+                },
+            };
+
+            resume_block = match (resume_block, contract_block) {
+                (Some(current), Some(block)) => Some(current.min(block)),
+                (current, block) => current.or(block),
+            };
+        }
+
+        Ok(resume_block)
+    }
+
+    async fn detect_start_block(&self, historical: &HistoricalSync, mut contract: RegisteredContract) -> Result<Option<u64>> {
+        let head = self.confirmed_head().await?;
+        let deployment_block = match historical.deployment_block(contract.address, head).await {
+            Ok(block) => block,
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to detect deployment block of contract {} on chain {}: {:?}",
+                    contract.name,
+                    self.chain_name,
+                    e
+                );
+                None
+            }
+        };
+
+        match deployment_block {
+            Some(block) => {
+                tracing::info!(
+                    "Detected deployment of contract {} on chain {} at block {}",
+                    contract.name,
+                    self.chain_name,
+                    block
+                );
+                // Remembered so that retried passes do not search again.
+                contract.start_block = Some(block);
+                self.contracts.register(contract);
+            }
+            None => tracing::debug!(
+                "No start block for contract {} on chain {}, indexing it live only",
+                contract.name,
+                self.chain_name
+            ),
+        }
+
+        Ok(deployment_block)
+    }
+
+    async fn confirmed_head(&self) -> Result<u64> {
+        self.retry("Reading the confirmed head", || self.listener.confirmed_block_number()).await
+    }
+
+    /// Retries failed passes from the last checkpoint written by the sync:
+    /// live listening only starts once the backfill is done.
+    async fn backfill(&self, historical: &HistoricalSync, from_block: u64, to_block: u64) -> Result<()> {
+        self.retry("Historical sync", || async {
+            let from_block = self.resume_block().await?
+                .map_or(from_block, |block| block.max(from_block));
+            historical.sync_to_block(from_block, to_block).await
+        }).await
+    }
+
+    /// Runs `call` until it succeeds, without giving up: an outage of the
+    /// endpoints or of MongoDB must not end the indexing of the chain.
+    async fn retry<R, F, Fut>(&self, operation: &str, call: F) -> Result<R>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<R>>,
+    {
+        let policy = ExponentialBackoff {
+            max_interval: MAX_RETRY_INTERVAL,
+            max_elapsed_time: None,
+            ..ExponentialBackoff::default()
+        };
+
+        backoff::future::retry(policy, || async {
+            call().await.map_err(|e| {
+                tracing::error!("{} failed on {}: {:?}", operation, self.chain_name, e);
+                backoff::Error::transient(e)
+            })
+        }).await
+    }
+}
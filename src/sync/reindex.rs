@@ -0,0 +1,59 @@
+use crate::db::events::EventStore;
+use crate::error::Result;
+use crate::sync::historical::HistoricalSync;
+use mongodb::bson::DateTime;
+use mongodb::Database;
+
+/// Changes made to the `events` collection by a reindex.
+#[derive(Debug, Default)]
+pub struct ReindexReport {
+    pub inserted: u64,
+    pub updated: u64,
+    pub unchanged: u64,
+    pub removed: u64,
+}
+
+/// Re-indexes a block range of a chain, typically after an ABI change. The
+/// range is synced again over the stored events, then the events the new pass
+/// did not write, because they no longer decode, are removed. Stored events
+/// stay in place until then, so an interrupted reindex loses nothing.
+pub struct Reindexer {
+    chain_name: String,
+    historical: HistoricalSync,
+    events: EventStore,
+}
+
+impl Reindexer {
+    pub fn new(chain_name: String, historical: HistoricalSync, db: &Database) -> Self {
+        Self {
+            chain_name,
+            historical,
+            events: EventStore::new(db),
+        }
+    }
+
+    pub async fn run(&self, from_block: u64, to_block: u64) -> Result<ReindexReport> {
+        let started_at = DateTime::now();
+        let before = self.events.contents_in_range(&self.chain_name, from_block, to_block, None).await?;
+
+        self.historical.resync_range(from_block, to_block).await?;
+
+        let after = self.events
+            .contents_in_range(&self.chain_name, from_block, to_block, Some(started_at))
+            .await?;
+        let removed = self.events
+            .delete_indexed_before(&self.chain_name, from_block, to_block, started_at)
+            .await?;
+
+        let mut report = ReindexReport { removed, ..ReindexReport::default() };
+        for (identity, event) in &after {
+            match before.get(identity) {
+                None => report.inserted += 1,
+                Some(previous) if previous != event => report.updated += 1,
+                Some(_) => report.unchanged += 1,
+            }
+        }
+
+        Ok(report)
+    }
+}
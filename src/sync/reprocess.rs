@@ -0,0 +1,95 @@
+use crate::chain::block_timestamps::BlockTimestamps;
+use crate::chain::connection::ChainConnection;
+use crate::db::events::EventStore;
+use crate::db::failed_logs::FailedLogStore;
+use crate::db::models::EventLog;
+use crate::decoder::registry::ContractRegistry;
+use crate::error::Result;
+use mongodb::Database;
+use web3::types::Log;
+
+/// Outcome of a pass over the dead-letter collection of a chain.
+#[derive(Debug, Default)]
+pub struct ReprocessReport {
+    pub reprocessed: u64,
+    pub failed: u64,
+}
+
+/// Retries the logs kept in `failed_logs`, typically after an ABI was fixed
+/// or a storage outage was resolved. Logs that go through are removed from
+/// the collection; the others have their attempt count bumped.
+pub struct FailedLogReprocessor {
+    chain_name: String,
+    contracts: ContractRegistry,
+    connection: ChainConnection,
+    events: EventStore,
+    failed_logs: FailedLogStore,
+    block_timestamps: BlockTimestamps,
+}
+
+impl FailedLogReprocessor {
+    pub fn new(chain_name: String, contracts: ContractRegistry, connection: ChainConnection, db: &Database) -> Self {
+        Self {
+            chain_name,
+            contracts,
+            connection,
+            events: EventStore::new(db),
+            failed_logs: FailedLogStore::new(db),
+            block_timestamps: BlockTimestamps::default(),
+        }
+    }
+
+    pub async fn run(&self) -> Result<ReprocessReport> {
+        let mut report = ReprocessReport::default();
+
+        for failed in self.failed_logs.load(&self.chain_name).await? {
+            let log = match failed.to_log() {
+                Ok(log) => log,
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed log {} #{} on {} cannot be rebuilt: {:?}",
+                        failed.transaction_hash,
+                        failed.log_index,
+                        self.chain_name,
+                        e
+                    );
+                    self.failed_logs.record_attempt(&failed, &e).await?;
+                    report.failed += 1;
+                    continue;
+                }
+            };
+
+            match self.store(&log).await {
+                Ok(()) => {
+                    self.failed_logs.remove(&failed).await?;
+                    report.reprocessed += 1;
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed log {} #{} on {} still fails: {:?}",
+                        failed.transaction_hash,
+                        failed.log_index,
+                        self.chain_name,
+                        e
+                    );
+                    self.failed_logs.record(&self.chain_name, &log, &e).await?;
+                    report.failed += 1;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn store(&self, log: &Log) -> Result<()> {
+        let (event_name, params) = self.contracts.decode_log(log)?;
+
+        let block_hash = log.block_hash.unwrap_or_default();
+        let block_timestamp = self.block_timestamps
+            .get_or_fetch(block_hash, || self.connection.block_by_hash(block_hash))
+            .await?;
+
+        let event_log = EventLog::new(&self.chain_name, &event_name, params, log, block_timestamp);
+        self.events.upsert(&event_log).await
+    }
+}
@@ -0,0 +1,245 @@
+use ethabi::{ethereum_types::{H160, H256, U256}, RawLog, Token};
+use evm_indexer::config::{NumericConfig, NumericMode};
+use evm_indexer::{Error, EventDecoder};
+use mongodb::bson::{doc, Bson};
+use std::collections::HashMap;
+
+const ORDER_ABI: &str = r#"[
+    {
+        "type": "event",
+        "name": "OrderPlaced",
+        "anonymous": false,
+        "inputs": [
+            { "name": "owner", "type": "address", "indexed": true },
+            {
+                "name": "order",
+                "type": "tuple",
+                "indexed": false,
+                "components": [
+                    { "name": "id", "type": "uint256" },
+                    {
+                        "name": "items",
+                        "type": "tuple[]",
+                        "components": [
+                            { "name": "token", "type": "address" },
+                            { "name": "amount", "type": "uint256" }
+                        ]
+                    },
+                    { "name": "tag", "type": "bytes4" }
+                ]
+            },
+            { "name": "flags", "type": "uint8[3]", "indexed": false },
+            { "name": "reference", "type": "bytes32", "indexed": false }
+        ]
+    }
+]"#;
+
+fn item(token: H160, amount: u64) -> Token {
+    Token::Tuple(vec![Token::Address(token), Token::Uint(U256::from(amount))])
+}
+
+fn order_log(abi: &str) -> RawLog {
+    let contract = ethabi::Contract::load(abi.as_bytes()).unwrap();
+    let event = contract.event("OrderPlaced").unwrap();
+
+    let owner = H160::repeat_byte(0x11);
+    let data = ethabi::encode(&[
+        Token::Tuple(vec![
+            Token::Uint(U256::from(42u64)),
+            Token::Array(vec![
+                item(H160::repeat_byte(0x22), 1_000),
+                item(H160::repeat_byte(0x33), 2_000),
+            ]),
+            Token::FixedBytes(vec![0xde, 0xad, 0xbe, 0xef]),
+        ]),
+        Token::FixedArray(vec![
+            Token::Uint(U256::from(1u8)),
+            Token::Uint(U256::from(2u8)),
+            Token::Uint(U256::from(3u8)),
+        ]),
+        Token::FixedBytes(vec![0xab; 32]),
+    ]);
+
+    RawLog {
+        topics: vec![event.signature(), H256::from(owner)],
+        data,
+    }
+}
+
+#[test]
+fn decodes_nested_tuples_and_arrays_with_component_names() {
+    let decoder = EventDecoder::from_json(ORDER_ABI).unwrap();
+
+    let (event_name, params) = decoder.decode_log(order_log(ORDER_ABI)).unwrap();
+
+    assert_eq!(event_name, "OrderPlaced");
+    assert_eq!(
+        params,
+        doc! {
+            "owner": format!("{:?}", H160::repeat_byte(0x11)),
+            "order": {
+                "id": "42",
+                "items": [
+                    { "token": format!("{:?}", H160::repeat_byte(0x22)), "amount": "1000" },
+                    { "token": format!("{:?}", H160::repeat_byte(0x33)), "amount": "2000" },
+                ],
+                "tag": "deadbeef",
+            },
+            "flags": ["1", "2", "3"],
+            "reference": "ab".repeat(32),
+        }
+    );
+}
+
+#[test]
+fn falls_back_to_positional_keys_without_component_names() {
+    let contract = ethabi::Contract::load(ORDER_ABI.as_bytes()).unwrap();
+    let decoder = EventDecoder::new(std::sync::Arc::new(contract));
+
+    let (_, params) = decoder.decode_log(order_log(ORDER_ABI)).unwrap();
+
+    let order = params.get_document("order").unwrap();
+    assert_eq!(order.get_str("0").unwrap(), "42");
+    assert_eq!(order.get_str("2").unwrap(), "deadbeef");
+
+    let items = order.get_array("1").unwrap();
+    assert_eq!(
+        items[0],
+        Bson::Document(doc! { "0": format!("{:?}", H160::repeat_byte(0x22)), "1": "1000" })
+    );
+}
+
+const TRANSFER_ABI: &str = r#"[
+    {
+        "type": "event",
+        "name": "Transfer",
+        "anonymous": false,
+        "inputs": [
+            { "name": "amount", "type": "uint256", "indexed": false },
+            { "name": "supply", "type": "uint256", "indexed": false },
+            { "name": "delta", "type": "int256", "indexed": false },
+            { "name": "decimals", "type": "uint8", "indexed": false }
+        ]
+    }
+]"#;
+
+fn transfer_log(amount: U256, supply: U256, delta: i64, decimals: u8) -> RawLog {
+    let contract = ethabi::Contract::load(TRANSFER_ABI.as_bytes()).unwrap();
+    let delta = if delta < 0 {
+        !U256::from(delta.unsigned_abs()) + U256::one()
+    } else {
+        U256::from(delta)
+    };
+
+    RawLog {
+        topics: vec![contract.event("Transfer").unwrap().signature()],
+        data: ethabi::encode(&[
+            Token::Uint(amount),
+            Token::Uint(supply),
+            Token::Int(delta),
+            Token::Uint(U256::from(decimals)),
+        ]),
+    }
+}
+
+#[test]
+fn stores_numbers_according_to_numeric_mode() {
+    let numeric = NumericConfig {
+        default: NumericMode::Int64,
+        events: HashMap::new(),
+        fields: HashMap::from([
+            ("Transfer.amount".to_string(), NumericMode::Decimal128),
+            ("Transfer.supply".to_string(), NumericMode::Decimal128),
+            ("Transfer.delta".to_string(), NumericMode::Dual),
+        ]),
+    };
+    let decoder = EventDecoder::from_json(TRANSFER_ABI).unwrap().with_numeric(numeric);
+
+    let amount = U256::exp10(20);
+    let supply = U256::exp10(40);
+    let (_, params) = decoder.decode_log(transfer_log(amount, supply, -5, 18)).unwrap();
+
+    assert_eq!(
+        params.get("amount"),
+        Some(&Bson::Decimal128("100000000000000000000".parse().unwrap()))
+    );
+    assert_eq!(params.get_str("supply").unwrap(), supply.to_string());
+    assert_eq!(
+        params.get_document("delta").unwrap(),
+        &doc! {
+            "value": "-5",
+            "sortable": format!("7{}b", "f".repeat(62)),
+        }
+    );
+    assert_eq!(params.get("decimals"), Some(&Bson::Int64(18)));
+}
+
+#[test]
+fn stores_signed_integers_as_signed_decimal_strings() {
+    let decoder = EventDecoder::from_json(TRANSFER_ABI).unwrap();
+
+    let (_, params) = decoder.decode_log(transfer_log(U256::one(), U256::one(), -42, 6)).unwrap();
+
+    assert_eq!(params.get_str("delta").unwrap(), "-42");
+    assert_eq!(params.get_str("decimals").unwrap(), "6");
+}
+
+const OVERLOADED_ABI: &str = r#"[
+    {
+        "type": "event",
+        "name": "Transfer",
+        "anonymous": false,
+        "inputs": [
+            { "name": "from", "type": "address", "indexed": true },
+            { "name": "to", "type": "address", "indexed": true },
+            { "name": "value", "type": "uint256", "indexed": false }
+        ]
+    },
+    {
+        "type": "event",
+        "name": "Transfer",
+        "anonymous": false,
+        "inputs": [
+            { "name": "from", "type": "address", "indexed": true },
+            { "name": "to", "type": "address", "indexed": true },
+            { "name": "tokenId", "type": "uint256", "indexed": true }
+        ]
+    }
+]"#;
+
+fn transfer_signature() -> H256 {
+    let contract = ethabi::Contract::load(OVERLOADED_ABI.as_bytes()).unwrap();
+    contract.event("Transfer").unwrap().signature()
+}
+
+#[test]
+fn picks_the_overload_matching_the_indexed_topics() {
+    let decoder = EventDecoder::from_json(OVERLOADED_ABI).unwrap();
+    let from = H256::from(H160::repeat_byte(0x01));
+    let to = H256::from(H160::repeat_byte(0x02));
+    let amount = ethabi::encode(&[Token::Uint(U256::from(7u8))]);
+
+    let erc20 = RawLog { topics: vec![transfer_signature(), from, to], data: amount.clone() };
+    let (_, params) = decoder.decode_log(erc20).unwrap();
+    assert_eq!(params.get_str("value").unwrap(), "7");
+
+    let erc721 = RawLog { topics: vec![transfer_signature(), from, to, H256::from_low_u64_be(7)], data: vec![] };
+    let (_, params) = decoder.decode_log(erc721).unwrap();
+    assert_eq!(params.get_str("tokenId").unwrap(), "7");
+}
+
+#[test]
+fn distinguishes_unknown_signatures_from_undecodable_data() {
+    let decoder = EventDecoder::from_json(OVERLOADED_ABI).unwrap();
+    let from = H256::from(H160::repeat_byte(0x01));
+    let to = H256::from(H160::repeat_byte(0x02));
+
+    let unknown = RawLog { topics: vec![H256::repeat_byte(0xff), from, to], data: vec![] };
+    assert!(matches!(decoder.decode_log(unknown), Err(Error::UnknownEvent)));
+
+    let truncated = RawLog { topics: vec![transfer_signature(), from, to], data: vec![0u8; 4] };
+    assert!(matches!(
+        decoder.decode_log(truncated),
+        Err(Error::EventDecodeError(name, _)) if name == "Transfer"
+    ));
+}